@@ -0,0 +1,252 @@
+use std::{str::FromStr, sync::OnceLock};
+
+use image::{buffer::ConvertBuffer, Pixel};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{fill, screen::ScreenImage, PartImage, PartPixel, RenderError, BLACK};
+
+/// How part images are blended into the frame
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compositing {
+    /// Blend 8-bit sRGB values directly, fast but causes banding and dark fringes
+    #[default]
+    Srgb,
+    /// Blend in linear light with `f32` precision and convert to sRGB once per frame
+    Linear,
+}
+
+impl FromStr for Compositing {
+    type Err = RenderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "srgb" => Ok(Compositing::Srgb),
+            "linear" => Ok(Compositing::Linear),
+            _ => Err(RenderError::InitializationError(format!(
+                "Unknown compositing mode '{}'.",
+                s
+            ))),
+        }
+    }
+}
+
+/// The frame being composited, starts as opaque black
+pub(crate) enum Canvas {
+    Srgb(PartImage),
+    Linear {
+        width: u32,
+        height: u32,
+        data: Vec<[f32; 3]>,
+    },
+    /// A transparent layer, the children of a translucent group are composited on it before it
+    /// is blended into the frame, premultiplied and in linear light if `linear`
+    Layer {
+        width: u32,
+        linear: bool,
+        data: Vec<[f32; 4]>,
+    },
+}
+
+impl Canvas {
+    pub(crate) fn new(mode: Compositing, width: u32, height: u32) -> Canvas {
+        match mode {
+            Compositing::Srgb => {
+                let mut image = PartImage::new(width, height);
+                fill(&mut image, BLACK);
+                Canvas::Srgb(image)
+            }
+            Compositing::Linear => Canvas::Linear {
+                width,
+                height,
+                data: vec![[0.0; 3]; (width * height) as usize],
+            },
+        }
+    }
+
+    /// A transparent layer of the same size, blended in the same color space
+    pub(crate) fn layer(&self) -> Canvas {
+        let (width, height, linear) = match self {
+            Canvas::Srgb(image) => (image.width(), image.height(), false),
+            Canvas::Linear { width, height, .. } => (*width, *height, true),
+            Canvas::Layer { width, linear, data } => (*width, data.len() as u32 / *width, *linear),
+        };
+        Canvas::Layer {
+            width,
+            linear,
+            data: vec![[0.0; 4]; (width * height) as usize],
+        }
+    }
+
+    /// Blend a layer made by `layer`, the alpha of the layer is multiplied by `factor` first
+    pub(crate) fn blend_layer(&mut self, layer: &Canvas, factor: f32) {
+        let (width, source) = match layer {
+            Canvas::Layer { width, data, .. } => (*width, data),
+            _ => return,
+        };
+        for (i, s) in source.iter().enumerate().filter(|(_, s)| s[3] > 0.0) {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            let alpha = s[3] * factor;
+            match self {
+                Canvas::Srgb(image) => {
+                    let dest = image.get_pixel_mut(x, y);
+                    for (d, s) in dest.0.iter_mut().zip(s.iter()).take(3) {
+                        *d = ((*d as f32 / 255.0 * (1.0 - alpha) + s * factor) * 255.0).round() as u8;
+                    }
+                }
+                Canvas::Linear { data, .. } => {
+                    for (d, s) in data[i].iter_mut().zip(s.iter()) {
+                        *d = *d * (1.0 - alpha) + s * factor;
+                    }
+                }
+                Canvas::Layer { data, .. } => {
+                    for (d, s) in data[i].iter_mut().zip(s.iter()) {
+                        *d = *d * (1.0 - alpha) + s * factor;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Blend `pixel` at `(x, y)`, the alpha of `pixel` is multiplied by `factor` first
+    pub(crate) fn blend(&mut self, x: u32, y: u32, mut pixel: PartPixel, factor: f32) {
+        match self {
+            Canvas::Srgb(image) => {
+                if factor < 1.0 {
+                    pixel.0[3] = (pixel.0[3] as f32 * factor).round() as u8;
+                }
+                image.get_pixel_mut(x, y).blend(&pixel)
+            }
+            Canvas::Linear { width, data, .. } => {
+                let alpha = pixel.0[3] as f32 / 255.0 * factor;
+                if alpha <= 0.0 {
+                    return;
+                }
+                let lut = srgb_to_linear_lut();
+                let dest = &mut data[(y * *width + x) as usize];
+                for (d, s) in dest.iter_mut().zip(pixel.0.iter()) {
+                    // The canvas is always opaque, so "over" is a plain lerp
+                    *d += (lut[*s as usize] - *d) * alpha;
+                }
+            }
+            Canvas::Layer { width, linear, data } => {
+                let alpha = pixel.0[3] as f32 / 255.0 * factor;
+                if alpha <= 0.0 {
+                    return;
+                }
+                let lut = srgb_to_linear_lut();
+                let dest = &mut data[(y * *width + x) as usize];
+                for (d, s) in dest.iter_mut().zip(pixel.0.iter()).take(3) {
+                    let s = if *linear { lut[*s as usize] } else { *s as f32 / 255.0 };
+                    *d = s * alpha + *d * (1.0 - alpha);
+                }
+                dest[3] = alpha + dest[3] * (1.0 - alpha);
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> ScreenImage {
+        match self {
+            Canvas::Srgb(image) => image.convert(),
+            Canvas::Linear {
+                width,
+                height,
+                data,
+            } => ScreenImage::from_fn(width, height, |x, y| {
+                image::Rgb(data[(y * width + x) as usize].map(linear_to_srgb))
+            }),
+            // Layers are blended into a canvas, never shown
+            Canvas::Layer { width, data, .. } => ScreenImage::new(width, data.len() as u32 / width),
+        }
+    }
+}
+
+fn srgb_to_linear_lut() -> &'static [f32; 256] {
+    static LUT: OnceLock<[f32; 256]> = OnceLock::new();
+    LUT.get_or_init(|| {
+        let mut lut = [0f32; 256];
+        for (i, v) in lut.iter_mut().enumerate() {
+            let c = i as f32 / 255.0;
+            *v = if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            };
+        }
+        lut
+    })
+}
+
+fn linear_to_srgb(v: f32) -> u8 {
+    let v = v.clamp(0.0, 1.0);
+    let c = if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn test_linear() {
+        // Round-trip of every opaque value is lossless
+        for c in 0..=255u8 {
+            let mut canvas = Canvas::new(Compositing::Linear, 1, 1);
+            canvas.blend(0, 0, Rgba::<u8>([c, c, c, 255]), 1.0);
+            assert_eq!(canvas.finish().get_pixel(0, 0), &Rgb::<u8>([c, c, c]));
+        }
+
+        // Reference values are computed with the sRGB transfer functions in f64
+        // 50% red over black
+        let mut canvas = Canvas::new(Compositing::Linear, 1, 1);
+        canvas.blend(0, 0, Rgba::<u8>([255, 0, 0, 127]), 1.0);
+        assert_eq!(canvas.finish().get_pixel(0, 0), &Rgb::<u8>([187, 0, 0]));
+
+        // 50% red over green, sRGB blending gives a dark [127, 127, 0]
+        let mut canvas = Canvas::new(Compositing::Linear, 1, 1);
+        canvas.blend(0, 0, Rgba::<u8>([0, 255, 0, 255]), 1.0);
+        canvas.blend(0, 0, Rgba::<u8>([255, 0, 0, 127]), 1.0);
+        assert_eq!(canvas.finish().get_pixel(0, 0), &Rgb::<u8>([187, 188, 0]));
+
+        // Two stacked 50% white layers over black
+        let mut canvas = Canvas::new(Compositing::Linear, 1, 1);
+        canvas.blend(0, 0, Rgba::<u8>([255, 255, 255, 127]), 1.0);
+        canvas.blend(0, 0, Rgba::<u8>([255, 255, 255, 127]), 1.0);
+        assert_eq!(canvas.finish().get_pixel(0, 0), &Rgb::<u8>([224, 224, 224]));
+
+        // Opacity factor is applied without truncation
+        let mut canvas = Canvas::new(Compositing::Linear, 1, 1);
+        canvas.blend(0, 0, Rgba::<u8>([255, 255, 255, 255]), 0.5);
+        assert_eq!(canvas.finish().get_pixel(0, 0), &Rgb::<u8>([188, 188, 188]));
+    }
+
+    #[test]
+    fn test_layer() {
+        // Overlapping pixels of a layer don't show through each other
+        for mode in [Compositing::Srgb, Compositing::Linear] {
+            let mut canvas = Canvas::new(mode, 1, 1);
+            let mut layer = canvas.layer();
+            layer.blend(0, 0, Rgba::<u8>([255, 0, 0, 255]), 1.0);
+            layer.blend(0, 0, Rgba::<u8>([0, 0, 255, 255]), 1.0);
+            canvas.blend_layer(&layer, 0.5);
+            let mut expected = Canvas::new(mode, 1, 1);
+            expected.blend(0, 0, Rgba::<u8>([0, 0, 255, 255]), 0.5);
+            assert_eq!(canvas.finish(), expected.finish());
+        }
+    }
+
+    #[test]
+    fn test_srgb() {
+        let mut canvas = Canvas::new(Compositing::Srgb, 1, 1);
+        canvas.blend(0, 0, Rgba::<u8>([0, 255, 0, 255]), 1.0);
+        canvas.blend(0, 0, Rgba::<u8>([255, 0, 0, 127]), 1.0);
+        assert_eq!(canvas.finish().get_pixel(0, 0), &Rgb::<u8>([127, 127, 0]));
+    }
+}
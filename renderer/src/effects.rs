@@ -0,0 +1,252 @@
+use schemars::JsonSchema;
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+
+use crate::{screen::ScreenImage, DEFAULT_HEIGHT, DEFAULT_WIDTH};
+
+/// Blurs are computed for every pixel of every frame, larger values than the screen only cost
+/// time, and can freeze the display
+const MAX_RADIUS: u32 = if DEFAULT_WIDTH > DEFAULT_HEIGHT { DEFAULT_WIDTH } else { DEFAULT_HEIGHT };
+const MAX_SIGMA: f32 = MAX_RADIUS as f32;
+
+fn deserialize_radius<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let radius = u32::deserialize(deserializer)?;
+    if radius > MAX_RADIUS {
+        return Err(D::Error::custom(format!("radius {} is larger than the screen, max is {}", radius, MAX_RADIUS)));
+    }
+    Ok(radius)
+}
+
+fn deserialize_sigma<'de, D>(deserializer: D) -> Result<f32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let sigma = f32::deserialize(deserializer)?;
+    if !sigma.is_finite() || sigma > MAX_SIGMA {
+        return Err(D::Error::custom(format!("sigma {} is out of range, max is {}", sigma, MAX_SIGMA)));
+    }
+    Ok(sigma)
+}
+
+/// Ordered dithering threshold map
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Full-screen post-processing effect, applied to the composited frame before it's sent to the
+/// display. Effects are applied in the order they appear in the chain.
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum Effect {
+    BoxBlur {
+        #[serde(deserialize_with = "deserialize_radius")]
+        radius: u32,
+    },
+    GaussianBlur {
+        #[serde(deserialize_with = "deserialize_sigma")]
+        sigma: f32,
+    },
+    /// Blur the pixels brighter than `threshold` and add them back on top of the frame
+    Bloom {
+        threshold: u8,
+        #[serde(deserialize_with = "deserialize_sigma")]
+        sigma: f32,
+        strength: f32,
+    },
+    /// Reduce every channel to `levels` values with a 4x4 Bayer matrix
+    Dither {
+        levels: u8,
+    },
+    Invert,
+    HueRotate {
+        degrees: f32,
+    },
+    /// Darken every other row to emulate a CRT, `intensity` is in range `0.0..=1.0`
+    Scanlines {
+        intensity: f32,
+    },
+}
+
+impl Effect {
+    pub(crate) fn apply(&self, image: &mut ScreenImage) {
+        match self {
+            Effect::BoxBlur { radius } => box_blur(image, *radius),
+            Effect::GaussianBlur { sigma } => {
+                if *sigma > 0.0 {
+                    *image = image::imageops::blur(image, *sigma);
+                }
+            }
+            Effect::Bloom {
+                threshold,
+                sigma,
+                strength,
+            } => bloom(image, *threshold, *sigma, *strength),
+            Effect::Dither { levels } => dither(image, *levels),
+            Effect::Invert => {
+                for p in image.pixels_mut() {
+                    for c in p.0.iter_mut() {
+                        *c = 255 - *c;
+                    }
+                }
+            }
+            Effect::HueRotate { degrees } => hue_rotate(image, *degrees),
+            Effect::Scanlines { intensity } => {
+                let factor = 1.0 - intensity.clamp(0.0, 1.0);
+                for (_, y, p) in image.enumerate_pixels_mut() {
+                    if y % 2 == 1 {
+                        for c in p.0.iter_mut() {
+                            *c = (*c as f32 * factor).round() as u8;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn box_blur(image: &mut ScreenImage, radius: u32) {
+    if radius == 0 {
+        return;
+    }
+    let (w, h) = image.dimensions();
+    let r = radius as i64;
+    // Separable blur, horizontal pass then vertical pass, edges are clamped
+    for horizontal in [true, false] {
+        let src = image.clone();
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = [0u32; 3];
+                for d in -r..=r {
+                    let (sx, sy) = if horizontal {
+                        ((x as i64 + d).clamp(0, w as i64 - 1) as u32, y)
+                    } else {
+                        (x, (y as i64 + d).clamp(0, h as i64 - 1) as u32)
+                    };
+                    for (s, c) in sum.iter_mut().zip(src.get_pixel(sx, sy).0.iter()) {
+                        *s += *c as u32;
+                    }
+                }
+                let n = (2 * radius + 1) as f32;
+                for (c, s) in image.get_pixel_mut(x, y).0.iter_mut().zip(sum.iter()) {
+                    *c = (*s as f32 / n).round() as u8;
+                }
+            }
+        }
+    }
+}
+
+fn bloom(image: &mut ScreenImage, threshold: u8, sigma: f32, strength: f32) {
+    let mut bright = image.clone();
+    for p in bright.pixels_mut() {
+        let luma = (0.2126 * p.0[0] as f32 + 0.7152 * p.0[1] as f32 + 0.0722 * p.0[2] as f32) as u8;
+        if luma < threshold {
+            p.0 = [0, 0, 0];
+        }
+    }
+    let glow = if sigma > 0.0 {
+        image::imageops::blur(&bright, sigma)
+    } else {
+        bright
+    };
+    for (p, g) in image.pixels_mut().zip(glow.pixels()) {
+        for c in 0..3 {
+            p.0[c] = (p.0[c] as f32 + g.0[c] as f32 * strength).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+fn dither(image: &mut ScreenImage, levels: u8) {
+    if levels < 2 {
+        return;
+    }
+    let step = 255.0 / (levels - 1) as f32;
+    for (x, y, p) in image.enumerate_pixels_mut() {
+        // Threshold in range (-0.5, 0.5) of a quantization step
+        let t = (BAYER_4X4[(y % 4) as usize][(x % 4) as usize] as f32 + 0.5) / 16.0 - 0.5;
+        for c in p.0.iter_mut() {
+            let q = (*c as f32 / step + t).round().clamp(0.0, (levels - 1) as f32);
+            *c = (q * step).round() as u8;
+        }
+    }
+}
+
+fn hue_rotate(image: &mut ScreenImage, degrees: f32) {
+    // Same matrix as the CSS `hue-rotate()` filter
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let m = [
+        [
+            0.213 + cos * 0.787 - sin * 0.213,
+            0.715 - cos * 0.715 - sin * 0.715,
+            0.072 - cos * 0.072 + sin * 0.928,
+        ],
+        [
+            0.213 - cos * 0.213 + sin * 0.143,
+            0.715 + cos * 0.285 + sin * 0.140,
+            0.072 - cos * 0.072 - sin * 0.283,
+        ],
+        [
+            0.213 - cos * 0.213 - sin * 0.787,
+            0.715 - cos * 0.715 + sin * 0.715,
+            0.072 + cos * 0.928 + sin * 0.072,
+        ],
+    ];
+    for p in image.pixels_mut() {
+        let [r, g, b] = p.0.map(|c| c as f32);
+        for (c, row) in p.0.iter_mut().zip(m.iter()) {
+            *c = (row[0] * r + row[1] * g + row[2] * b).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    #[test]
+    fn test_effects() {
+        let mut img = ScreenImage::from_pixel(4, 4, Rgb::<u8>([255, 0, 0]));
+        Effect::Invert.apply(&mut img);
+        assert_eq!(img.get_pixel(0, 0), &Rgb::<u8>([0, 255, 255]));
+
+        let mut img = ScreenImage::from_pixel(4, 4, Rgb::<u8>([255, 0, 0]));
+        Effect::HueRotate { degrees: 360.0 }.apply(&mut img);
+        assert_eq!(img.get_pixel(1, 1), &Rgb::<u8>([255, 0, 0]));
+
+        let mut img = ScreenImage::from_pixel(4, 4, Rgb::<u8>([200, 200, 200]));
+        Effect::Scanlines { intensity: 0.5 }.apply(&mut img);
+        assert_eq!(img.get_pixel(0, 0), &Rgb::<u8>([200, 200, 200]));
+        assert_eq!(img.get_pixel(0, 1), &Rgb::<u8>([100, 100, 100]));
+
+        // A blurred single dot spreads evenly over its neighbours
+        let mut img = ScreenImage::new(5, 5);
+        img.put_pixel(2, 2, Rgb::<u8>([90, 90, 90]));
+        Effect::BoxBlur { radius: 1 }.apply(&mut img);
+        assert_eq!(img.get_pixel(1, 1), &Rgb::<u8>([10, 10, 10]));
+        assert_eq!(img.get_pixel(0, 0), &Rgb::<u8>([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_limits() {
+        let effects = |json: &str| serde_json::from_str::<Vec<Effect>>(json);
+        assert!(effects(r#"[{"type": "BoxBlur", "radius": 64}, {"type": "GaussianBlur", "sigma": 1.5}]"#).is_ok());
+        assert!(effects(r#"[{"type": "Invert"}, {"type": "BoxBlur", "radius": 1000000}]"#).is_err());
+        assert!(effects(r#"[{"type": "GaussianBlur", "sigma": 1e30}]"#).is_err());
+        assert!(effects(r#"[{"type": "Bloom", "threshold": 200, "sigma": 1e9, "strength": 1.0}]"#).is_err());
+        // The whole chain of an `Effects` message is rejected
+        let msg = r#"{"type": "Effects", "effects": [{"type": "Invert"}, {"type": "BoxBlur", "radius": 4294967295}]}"#;
+        assert!(serde_json::from_str::<crate::message::NeoClockMessage>(msg).is_err());
+    }
+
+    #[test]
+    fn test_dither() {
+        // 50% gray dithered to 2 levels is a checker-like pattern with half of the pixels lit
+        let mut img = ScreenImage::from_pixel(4, 4, Rgb::<u8>([128, 128, 128]));
+        Effect::Dither { levels: 2 }.apply(&mut img);
+        let lit = img.pixels().filter(|p| p.0 == [255, 255, 255]).count();
+        let dark = img.pixels().filter(|p| p.0 == [0, 0, 0]).count();
+        assert_eq!(lit + dark, 16);
+        assert_eq!(lit, 8);
+    }
+}
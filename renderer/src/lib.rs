@@ -1,14 +1,32 @@
+mod compositing;
+mod effects;
+mod mask;
 mod movers;
+mod queue;
 mod widgets;
+mod schema;
 mod screen;
+mod state;
 
-pub use screen::Screen;
+pub use compositing::Compositing;
+pub use effects::Effect;
+pub use mask::MaskConfig;
+pub use queue::{OverflowPolicy, QueueConfig};
+pub use schema::message_schema;
+pub use screen::{DisplayStatus, Health, Screen, ScreenMonitor, WidgetStatus};
+pub use state::{FlyerState, RuntimeState, WidgetState};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Metadata, Schema, SchemaObject},
+    JsonSchema,
+};
 use serde::Serializer;
 pub use widgets::message;
 pub use widgets::Widget;
 pub(crate) type PartPixel = image::Rgba<u8>;
 pub(crate) type PartImage = ImageBuffer<PartPixel, Vec<u8>>;
-pub(crate) use screen::{Part, PartCache, PartChannel, PartSender};
+pub(crate) use queue::{PartChannel, PartSender};
+pub(crate) use screen::{Part, PartCache, Received, SettingsCache};
 
 use image::{ImageBuffer, Pixel};
 use serde::{    de::Error,Deserialize, Deserializer };
@@ -31,6 +49,12 @@ pub enum RenderError {
     #[error("File at '{0}' is not a valid font.")]
     FontError(String),
 
+    #[error("Widget name '{0}' is used more than once.")]
+    DuplicateWidgetName(String),
+
+    #[error("The queue of widget {0} is full.")]
+    QueueFull(usize),
+
     #[error(transparent)]
     ReqwestError(#[from] reqwest::Error),
 
@@ -54,11 +78,18 @@ pub trait Drawable {
     fn set_pixel(&mut self, x: u32, y: u32, r: u8, g: u8, b: u8);
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct WidgetConf {
+    /// Optional unique name, messages can address the widget by this name instead of its index
+    pub name: Option<String>,
     pub x: u32,
     pub y: u32,
     pub visible: Option<bool>,
+    pub opacity: Option<f32>,
+    pub mask: Option<MaskConfig>,
+    /// The queue of messages waiting for the widget
+    #[serde(default)]
+    pub queue: QueueConfig,
     #[serde(flatten)]
     pub widget: Widget,
 }
@@ -71,6 +102,27 @@ pub const DEFAULT_GIF2_ID: usize = 2;
 pub const DEFAULT_GIF3_ID: usize = 3;
 pub const DEFAULT_GIF4_ID: usize = 4;
 pub const DEFAULT_FLYER_ID: usize = 7;
+/// TTL in seconds of Flyer messages sent as plain text
+pub const DEFAULT_FLYER_TTL: u32 = 30;
+
+/// Check that widget names, including the ones of widgets in groups, are unique
+pub fn check_names(widgets: &[WidgetConf]) -> Result<(), RenderError> {
+    fn collect<'a>(widgets: &'a [WidgetConf], names: &mut Vec<&'a str>) -> Result<(), RenderError> {
+        for w in widgets {
+            if let Some(name) = &w.name {
+                if names.contains(&name.as_str()) {
+                    return Err(RenderError::DuplicateWidgetName(name.to_owned()));
+                }
+                names.push(name);
+            }
+            if let Widget::Group(g) = &w.widget {
+                collect(&g.children, names)?;
+            }
+        }
+        Ok(())
+    }
+    collect(widgets, &mut Vec::new())
+}
 
 pub(crate) fn deserialize_pixel<'de, D>(deserializer: D) -> Result<PartPixel, D::Error>
 where
@@ -79,19 +131,46 @@ where
     let buf = String::deserialize(deserializer)?;
     let color = buf
         .parse::<css_color_parser::Color>()
-        .map_err(|e| D::Error::custom(e.to_string()))?;
+        .map_err(|_| D::Error::custom(format!("invalid color '{}'", buf)))?;
     Ok(image::Rgba::<u8>([
         color.r,
         color.g,
         color.b,
-        (color.a * 255f32) as u8,
+        (color.a * 255f32).round() as u8,
     ]))
 }
 
+/// The color string of an error of `deserialize_pixel`, colors in widgets are parsed from
+/// buffered values so the parsers can't tell where they are, this finds them in the source
+pub fn invalid_color(error: &str) -> Option<&str> {
+    let start = error.find("invalid color '")? + "invalid color '".len();
+    let len = error[start..].find('\'')?;
+    Some(&error[start..start + len])
+}
+
+/// Colors are CSS color strings in config files and messages
+pub(crate) fn pixel_schema(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        format: Some("color".to_string()),
+        metadata: Some(Box::new(Metadata {
+            description: Some("CSS color, e.g. `red`, `#ff0000` or `rgba(255, 0, 0, 0.5)`".to_string()),
+            examples: vec!["red".into(), "#ff0000".into(), "rgba(255, 0, 0, 0.5)".into()],
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+/// Inverse of `deserialize_pixel`
 pub(crate) fn serialize_pixel<S>(p: &PartPixel, ser: S) -> Result<S::Ok, S::Error>
 where S: Serializer 
 {
-    let s = format!("rgba({},{},{},{})", p.0[0], p.0[1], p.0[2], p.0[3] as f64 * 255.0);
+    let s = match p.0[3] {
+        255 => format!("rgb({},{},{})", p.0[0], p.0[1], p.0[2]),
+        a => format!("rgba({},{},{},{:.4})", p.0[0], p.0[1], p.0[2], a as f64 / 255.0),
+    };
     ser.serialize_str(&s)
 }
 
@@ -108,7 +187,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use serde::Deserialize;
+    use serde::{Deserialize, Serialize};
 
     use super::*;
 
@@ -131,7 +210,7 @@ mod tests {
         assert_eq!(s.p.0[0], 10);
         assert_eq!(s.p.0[1], 20);
         assert_eq!(s.p.0[2], 30);
-        assert_eq!(s.p.0[3], 127);
+        assert_eq!(s.p.0[3], 128);
 
         let j = r#"{
             "type": "Clock",
@@ -161,4 +240,43 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn test_invalid_color() {
+        let e = serde_json::from_str::<WidgetConf>(r#"{"type": "Solid", "x": 0, "y": 0, "width": 1, "height": 1, "color": "blurple"}"#)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(invalid_color(&e), Some("blurple"));
+        assert_eq!(invalid_color("missing field `x`"), None);
+    }
+
+    #[test]
+    fn test_ser_color() {
+        #[derive(Debug, Deserialize, Serialize)]
+        struct S {
+            #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+            p: PartPixel,
+        }
+        let s = S { p: image::Rgba([10, 20, 30, 255]) };
+        assert_eq!(serde_json::to_string(&s).unwrap(), r#"{"p":"rgb(10,20,30)"}"#);
+        for a in 0..=255 {
+            let s = S { p: image::Rgba([10, 20, 30, a]) };
+            let back: S = serde_json::from_str(&serde_json::to_string(&s).unwrap()).unwrap();
+            assert_eq!(back.p, s.p);
+        }
+    }
+
+    #[test]
+    fn test_check_names() {
+        let j = r#"[
+            {"type": "Solid", "name": "bg", "x": 0, "y": 0, "width": 1, "height": 1, "color": "red"},
+            {"type": "Group", "name": "card", "x": 0, "y": 0, "children": [
+                {"type": "Solid", "name": "bg", "x": 0, "y": 0, "width": 1, "height": 1, "color": "red"}
+            ]}
+        ]"#;
+        let mut w: Vec<WidgetConf> = serde_json::from_str(j).unwrap();
+        assert!(matches!(check_names(&w), Err(RenderError::DuplicateWidgetName(n)) if n == "bg"));
+        w[0].name = Some("background".to_string());
+        assert!(check_names(&w).is_ok());
+    }
 }
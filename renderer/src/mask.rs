@@ -0,0 +1,97 @@
+use image::GrayImage;
+use log::error;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{message::WidgetId, PartImage};
+
+/// Alpha mask of a part, the alpha of every pixel of the part is multiplied by the mask value at
+/// the same position, pixels outside of the mask are fully transparent.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskConfig {
+    /// Use the alpha channel of another part, aligned with that part on the screen.
+    /// The referenced part is used even if it's hidden, so a hidden part can serve as a pure mask.
+    Part(WidgetId),
+    /// Use a static grayscale image, aligned with the masked part, white means opaque.
+    Image(String),
+}
+
+pub(crate) enum Mask {
+    Part(usize),
+    Image(GrayImage),
+}
+
+impl Mask {
+    /// `names` are the names of all parts, in the order of their ids
+    pub(crate) fn load(conf: &MaskConfig, names: &[Option<String>]) -> Option<Mask> {
+        match conf {
+            MaskConfig::Part(id) => {
+                let idx = match id {
+                    WidgetId::Index(idx) => Some(*idx).filter(|idx| *idx < names.len()),
+                    WidgetId::Name(name) => names.iter().position(|n| n.as_ref() == Some(name)),
+                };
+                match idx {
+                    Some(idx) => Some(Mask::Part(idx)),
+                    None => {
+                        error!("Mask part {} doesn't exist, mask ignored.", id);
+                        None
+                    }
+                }
+            }
+            MaskConfig::Image(path) => match image::open(path) {
+                Ok(img) => Some(Mask::Image(img.into_luma8())),
+                Err(e) => {
+                    error!("Failed to load mask image '{}', error is '{}'.", path, e);
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// A mask resolved for the current frame, positioned in screen coordinates, borrows the image
+/// of the mask.
+pub(crate) struct MaskSampler<'a> {
+    image: MaskImage<'a>,
+    x: u32,
+    y: u32,
+}
+
+enum MaskImage<'a> {
+    /// The alpha channel of a part image is the mask
+    Alpha(&'a PartImage),
+    Gray(&'a GrayImage),
+}
+
+impl<'a> MaskSampler<'a> {
+    pub(crate) fn from_alpha(image: &'a PartImage, x: u32, y: u32) -> MaskSampler<'a> {
+        MaskSampler {
+            image: MaskImage::Alpha(image),
+            x,
+            y,
+        }
+    }
+
+    pub(crate) fn from_gray(image: &'a GrayImage, x: u32, y: u32) -> MaskSampler<'a> {
+        MaskSampler {
+            image: MaskImage::Gray(image),
+            x,
+            y,
+        }
+    }
+
+    /// Mask value at screen position `(x, y)`, in range `0.0..=1.0`
+    pub(crate) fn sample(&self, x: u32, y: u32) -> f32 {
+        if x < self.x || y < self.y {
+            return 0.0;
+        }
+        let (mx, my) = (x - self.x, y - self.y);
+        let value = match self.image {
+            MaskImage::Alpha(img) if mx < img.width() && my < img.height() => img.get_pixel(mx, my).0[3],
+            MaskImage::Gray(img) if mx < img.width() && my < img.height() => img.get_pixel(mx, my).0[0],
+            _ => 0,
+        };
+        value as f32 / 255.0
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+/// What to do with a message sent to a widget whose queue is full
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message to make room for the new one
+    #[default]
+    DropOldest,
+    /// Drop the new message
+    DropNewest,
+    /// Only keep the latest message, whatever the depth is, for widgets where only the last
+    /// state matters, e.g. the URL of a `Gif` widget
+    CoalesceLatest,
+}
+
+/// The message queue of a widget
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// Max number of messages waiting for the widget
+    pub depth: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            depth: 100,
+            overflow: Default::default(),
+        }
+    }
+}
+
+/// A queued message, configs are never dropped, a widget must not miss a config that is
+/// already reported as applied
+struct Queued {
+    msg: String,
+    config: bool,
+}
+
+struct Shared {
+    config: QueueConfig,
+    queue: Mutex<VecDeque<Queued>>,
+    notify: Notify,
+    senders: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+/// Sending never waits, so a stalled widget can't block the message handler
+pub(crate) struct PartSender(Arc<Shared>);
+
+/// Receiving end of the queue, owned by the widget task
+pub(crate) struct PartChannel(Arc<Shared>);
+
+pub(crate) fn channel(config: QueueConfig) -> (PartSender, PartChannel) {
+    let shared = Arc::new(Shared {
+        config: QueueConfig {
+            depth: config.depth.max(1),
+            ..config
+        },
+        queue: Default::default(),
+        notify: Notify::new(),
+        senders: AtomicUsize::new(1),
+        dropped: AtomicU64::new(0),
+    });
+    (PartSender(shared.clone()), PartChannel(shared))
+}
+
+/// Number of queued messages that are not configs, configs don't count in the depth
+fn messages(queue: &VecDeque<Queued>) -> usize {
+    queue.iter().filter(|q| !q.config).count()
+}
+
+impl PartSender {
+    /// Queue a message, returns `false` if it's dropped because the queue is full
+    pub(crate) fn push(&self, msg: String) -> bool {
+        let accepted = match self.0.queue.lock() {
+            Ok(mut queue) => {
+                let dropped = match self.0.config.overflow {
+                    OverflowPolicy::CoalesceLatest => {
+                        let len = queue.len();
+                        queue.retain(|q| q.config);
+                        len - queue.len()
+                    }
+                    _ if messages(&queue) < self.0.config.depth => 0,
+                    OverflowPolicy::DropOldest => match queue.iter().position(|q| !q.config) {
+                        Some(oldest) => queue.remove(oldest).map(|_| 1).unwrap_or_default(),
+                        None => 0,
+                    },
+                    OverflowPolicy::DropNewest => {
+                        self.0.dropped.fetch_add(1, Ordering::Relaxed);
+                        return false;
+                    }
+                };
+                self.0.dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+                queue.push_back(Queued { msg, config: false });
+                true
+            }
+            Err(_) => false,
+        };
+        self.0.notify.notify_one();
+        accepted
+    }
+
+    /// Queue a new config of the widget, whatever the overflow policy is, returns `false` only if
+    /// the queue is broken
+    pub(crate) fn push_config(&self, msg: String) -> bool {
+        let accepted = match self.0.queue.lock() {
+            Ok(mut queue) => {
+                queue.push_back(Queued { msg, config: true });
+                true
+            }
+            Err(_) => false,
+        };
+        self.0.notify.notify_one();
+        accepted
+    }
+
+    /// Whether `n` more messages can be queued without dropping the new ones, only
+    /// `drop-newest` queues reject messages
+    pub(crate) fn has_room(&self, n: usize) -> bool {
+        match self.0.config.overflow {
+            OverflowPolicy::DropNewest => self.0.queue.lock().is_ok_and(|q| messages(&q) + n <= self.0.config.depth),
+            _ => true,
+        }
+    }
+
+    /// Number of messages dropped so far
+    pub(crate) fn dropped(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for PartSender {
+    fn clone(&self) -> Self {
+        self.0.senders.fetch_add(1, Ordering::Relaxed);
+        Self(self.0.clone())
+    }
+}
+
+impl Drop for PartSender {
+    fn drop(&mut self) {
+        if self.0.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.notify.notify_one();
+        }
+    }
+}
+
+impl PartChannel {
+    /// Wait for the next message, `None` if all senders are gone and the queue is empty
+    pub(crate) async fn recv(&mut self) -> Option<String> {
+        loop {
+            if let Some(q) = self.0.queue.lock().ok()?.pop_front() {
+                return Some(q.msg);
+            }
+            if self.0.senders.load(Ordering::Acquire) == 0 {
+                return None;
+            }
+            // `notify_one` stores a permit if nobody is waiting, a push between the check above
+            // and here is not missed
+            self.0.notify.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn drain(channel: &mut PartChannel) -> Vec<String> {
+        let mut msgs = vec![];
+        while let Ok(Some(m)) = tokio::time::timeout(Duration::from_millis(10), channel.recv()).await {
+            msgs.push(m);
+        }
+        msgs
+    }
+
+    fn queue(depth: usize, overflow: OverflowPolicy) -> (PartSender, PartChannel) {
+        channel(QueueConfig { depth, overflow })
+    }
+
+    #[tokio::test]
+    async fn test_overflow() {
+        let (sender, mut channel) = queue(2, OverflowPolicy::DropOldest);
+        assert!(["1", "2", "3"].iter().all(|m| sender.push(m.to_string())));
+        assert_eq!(drain(&mut channel).await, ["2", "3"]);
+        assert_eq!(sender.dropped(), 1);
+
+        let (sender, mut channel) = queue(2, OverflowPolicy::DropNewest);
+        let accepted: Vec<bool> = ["1", "2", "3"].iter().map(|m| sender.push(m.to_string())).collect();
+        assert_eq!(accepted, [true, true, false]);
+        assert_eq!(drain(&mut channel).await, ["1", "2"]);
+        assert_eq!(sender.dropped(), 1);
+
+        let (sender, mut channel) = queue(2, OverflowPolicy::CoalesceLatest);
+        assert!(["1", "2", "3"].iter().all(|m| sender.push(m.to_string())));
+        assert_eq!(drain(&mut channel).await, ["3"]);
+        assert_eq!(sender.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_config() {
+        for overflow in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest, OverflowPolicy::CoalesceLatest] {
+            let (sender, mut channel) = queue(1, overflow);
+            sender.push("1".to_string());
+            sender.push_config("config".to_string());
+            sender.push("2".to_string());
+            sender.push("3".to_string());
+            assert!(drain(&mut channel).await.contains(&"config".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_has_room() {
+        let (sender, _channel) = queue(2, OverflowPolicy::DropNewest);
+        sender.push("1".to_string());
+        assert!(sender.has_room(1));
+        assert!(!sender.has_room(2));
+        let (sender, _channel) = queue(2, OverflowPolicy::DropOldest);
+        assert!(sender.has_room(10));
+    }
+
+    #[tokio::test]
+    async fn test_recv() {
+        let (sender, mut channel) = queue(2, OverflowPolicy::DropOldest);
+        let task = tokio::spawn(async move {
+            let mut msgs = vec![];
+            while let Some(m) = channel.recv().await {
+                msgs.push(m);
+            }
+            msgs
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        sender.push("1".to_string());
+        let other = sender.clone();
+        drop(sender);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        other.push("2".to_string());
+        drop(other);
+        assert_eq!(task.await.unwrap(), ["1", "2"]);
+    }
+}
@@ -0,0 +1,66 @@
+use schemars::{gen::SchemaSettings, schema::RootSchema, JsonSchema};
+
+use crate::message::Request;
+
+fn schema_for<T: JsonSchema>() -> RootSchema {
+    SchemaSettings::draft07().into_generator().into_root_schema_for::<T>()
+}
+
+/// JSON Schema of the messages accepted by the clock, with the optional correlation fields
+pub fn message_schema() -> RootSchema {
+    let mut schema = schema_for::<Request>();
+    schema.schema.metadata().title = Some("NeoClock message".to_string());
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonschema::JSONSchema;
+    use serde::de::DeserializeOwned;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::message::NeoClockMessage;
+
+    /// The schema must accept exactly what serde accepts
+    fn check<T: DeserializeOwned>(schema: &RootSchema, samples: &[(Value, bool)]) {
+        let schema = JSONSchema::compile(&serde_json::to_value(schema).unwrap()).unwrap();
+        for (sample, valid) in samples {
+            assert_eq!(schema.is_valid(sample), *valid, "schema, {}", sample);
+            assert_eq!(serde_json::from_value::<T>(sample.clone()).is_ok(), *valid, "serde, {}", sample);
+        }
+    }
+
+    #[test]
+    fn test_message_schema() {
+        let schema = message_schema();
+        check::<Request>(
+            &schema,
+            &[
+                (json!({"type": "Show", "id": 1}), true),
+                (json!({"type": "Hide", "id": "clock", "request_id": "1", "reply_to": "reply"}), true),
+                (json!({"type": "Move", "id": 1, "x": 1, "y": 2}), true),
+                (json!({"type": "Opacity", "id": 1, "opacity": 0.5}), true),
+                (json!({"type": "Power", "on": false}), true),
+                (json!({"type": "Brightness", "brightness": 128}), true),
+                (json!({"type": "Effects", "effects": [{"type": "Invert"}, {"type": "BoxBlur", "radius": 1}]}), true),
+                (json!({"type": "Solid", "id": 0, "color": "blue"}), true),
+                (json!({"type": "Clock", "id": 0}), true),
+                (json!({"type": "Calendar", "id": 0}), true),
+                (json!({"type": "Gif", "id": 1, "url": "robot.gif"}), true),
+                (json!({"type": "Flyer", "id": 7, "text": "Hello", "ttl": 10}), true),
+                (json!({"type": "Configure", "id": 2, "patch": {"text_color": "blue", "font_height": null}}), true),
+                (json!({"type": "Batch", "messages": [{"type": "Show", "id": 1}, {"type": "Batch", "messages": []}]}), true),
+                (json!({"type": "Scene", "name": "night"}), true),
+                (json!({"type": "Show"}), false),
+                (json!({"type": "Flyer", "id": 7, "text": "Hello"}), false),
+                (json!({"type": "Configure", "id": 2}), false),
+                (json!({"type": "Solid", "id": 0, "color": [0, 0, 255]}), false),
+                (json!({"type": "Batch", "messages": [{"type": "Reboot"}]}), false),
+            ],
+        );
+        // Messages produced by the clock itself are valid too
+        let msg: NeoClockMessage = serde_json::from_value(json!({"type": "Flyer", "id": 7, "text": "Hi", "ttl": 1})).unwrap();
+        check::<Request>(&schema, &[(serde_json::to_value(msg).unwrap(), true)]);
+    }
+}
@@ -1,17 +1,19 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
 
 use async_trait::async_trait;
-use image::{ImageBuffer, Pixel, buffer::ConvertBuffer};
-use log::debug;
+use image::ImageBuffer;
+use log::{debug, error};
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::{task::JoinHandle, sync::mpsc::Sender};
+use tokio::task::JoinHandle;
 
-use crate::{RenderError, message::{NeoClockMessage, msg_task}, WidgetConf, Widget, widgets::*, PartImage, DEFAULT_WIDTH, DEFAULT_HEIGHT, TRANSPARENT, HALF_WHITE, HALF_YELLOW, Drawable, BLACK, fill};
+use crate::{PartChannel, PartSender, RenderError, Compositing, Effect, compositing::Canvas, mask::{Mask, MaskSampler}, message::{MessageResult, MessageSender, NeoClockMessage, PartConfig, SceneCache, Target, WidgetId, msg_task}, state::{unix_now, FlyerState, RuntimeState, WidgetState}, WidgetConf, Widget, widgets::*, PartImage, DEFAULT_WIDTH, DEFAULT_HEIGHT, TRANSPARENT, HALF_WHITE, HALF_YELLOW, Drawable};
 
 pub(crate) type ScreenPixel = image::Rgb<u8>;
 pub(crate) type ScreenImage = ImageBuffer<ScreenPixel, Vec<u8>>;
-pub(crate) type PartSender = tokio::sync::mpsc::Sender<String>;
-pub(crate) type PartChannel = tokio::sync::mpsc::Receiver<String>;
 
 #[async_trait]
 pub(crate) trait Part {
@@ -22,93 +24,416 @@ pub(crate) trait Part {
         mut channel: PartChannel,
     ) -> Result<(), RenderError>;
 
-    async fn try_read<T>(&self, channel: &mut PartChannel, d: std::time::Duration) -> Option<T>
+    /// Wait up to `d` for a message, a new config sent by a `Configure` message replaces `self`
+    async fn read<T>(&mut self, channel: &mut PartChannel, d: std::time::Duration) -> Option<Received<T>>
     where
         T: DeserializeOwned,
+        Self: DeserializeOwned + Send,
     {
         if let Ok(Some(s)) = tokio::time::timeout(d, channel.recv()).await {
             debug!("Message body: '{}'", s);
-            serde_json::from_str(&s).ok()
+            if let Ok(c) = serde_json::from_str::<PartConfig<Self>>(&s) {
+                *self = c.configure;
+                return Some(Received::Configured);
+            }
+            serde_json::from_str(&s).ok().map(Received::Message)
         } else {
             None
         }
     }
 }
 
+/// What a widget got from its queue
+pub(crate) enum Received<T> {
+    Message(T),
+    /// The widget has a new config and must rebuild its state
+    Configured,
+}
+
+/// Whether the task of a widget is still alive
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    #[default]
+    Running,
+    Stopped,
+    Failed(String),
+}
+
+/// Runtime status of a widget
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WidgetStatus {
+    pub id: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<usize>,
+    pub x: u32,
+    pub y: u32,
+    pub visible: bool,
+    pub opacity: f32,
+    pub health: Health,
+    /// Messages dropped because the queue of the widget was full
+    pub dropped: u64,
+}
+
+impl WidgetStatus {
+    /// Whether the widget is the one addressed by `id`
+    pub fn matches(&self, id: &WidgetId) -> bool {
+        match id {
+            WidgetId::Index(idx) => self.id == *idx,
+            WidgetId::Name(name) => self.name.as_ref() == Some(name),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct PartContent {
     pub(crate) x: u32,
     pub(crate) y: u32,
     pub(crate) visible: bool,
+    pub(crate) opacity: f32,
     pub(crate) image: Option<PartImage>,
+    pub(crate) health: Health,
+    /// The last URL sent to a Gif widget
+    pub(crate) gif_url: Option<String>,
+    /// Flyer messages that have not expired yet
+    pub(crate) flyers: Vec<FlyerState>,
 }
 
 pub(crate) type PartCache = Arc<RwLock<PartContent>>;
 
+/// Screen-wide rendering settings, can be changed at runtime by messages
+#[derive(Clone, Debug)]
+pub(crate) struct RenderSettings {
+    pub(crate) compositing: Compositing,
+    pub(crate) effects: Vec<Effect>,
+    pub(crate) power: bool,
+    pub(crate) brightness: u8,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            compositing: Default::default(),
+            effects: Default::default(),
+            power: true,
+            brightness: 255,
+        }
+    }
+}
+
+/// Screen-wide display state
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DisplayStatus {
+    pub power: bool,
+    pub brightness: u8,
+}
+
+pub(crate) type SettingsCache = Arc<RwLock<RenderSettings>>;
+
+/// Held for reading while a frame is rendered, and for writing while a batch of messages is
+/// applied, so a frame never shows half of a batch
+pub(crate) type FrameLock = Arc<RwLock<()>>;
+
 struct PartTask {
+    name: Option<String>,
+    kind: &'static str,
     content: PartCache,
     sender: PartSender,
     join_handler: JoinHandle<Result<(), RenderError>>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    mask: Option<Mask>,
 }
 
 pub struct Screen {
     pub width: u32,
     pub height: u32,
-    pub sender: Sender<NeoClockMessage>,
+    pub sender: MessageSender,
     parts: Vec<PartTask>,
+    roots: Vec<usize>,
+    settings: SettingsCache,
+    scenes: SceneCache,
+    frame_lock: FrameLock,
+    monitor: ScreenMonitor,
+}
+
+struct PartInfo {
+    name: Option<String>,
+    kind: &'static str,
+    parent: Option<usize>,
+    content: PartCache,
+    queue: PartSender,
+}
+
+/// Read-only view of a `Screen`, can be cloned and used from other tasks
+#[derive(Clone)]
+pub struct ScreenMonitor {
+    parts: Arc<Vec<PartInfo>>,
+    settings: SettingsCache,
+    frame: Arc<RwLock<ScreenImage>>,
+}
+
+impl ScreenMonitor {
+    /// Status of all widgets, in the order of their ids
+    pub fn status(&self) -> Vec<WidgetStatus> {
+        self.parts
+            .iter()
+            .enumerate()
+            .filter_map(|(id, part)| {
+                let read_guard = part.content.read().ok()?;
+                Some(WidgetStatus {
+                    id,
+                    name: part.name.clone(),
+                    kind: part.kind,
+                    group: part.parent,
+                    x: read_guard.x,
+                    y: read_guard.y,
+                    visible: read_guard.visible,
+                    opacity: read_guard.opacity,
+                    health: read_guard.health.clone(),
+                    dropped: part.queue.dropped(),
+                })
+            })
+            .collect()
+    }
+
+    /// State of the widgets changed by messages, to be restored after a restart
+    pub fn runtime_state(&self) -> RuntimeState {
+        let now = unix_now();
+        let widgets = self
+            .parts
+            .iter()
+            .enumerate()
+            .filter_map(|(id, part)| {
+                let read_guard = part.content.read().ok()?;
+                Some(WidgetState {
+                    id,
+                    name: part.name.clone(),
+                    kind: part.kind.to_owned(),
+                    visible: read_guard.visible,
+                    x: read_guard.x,
+                    y: read_guard.y,
+                    opacity: read_guard.opacity,
+                    gif_url: read_guard.gif_url.clone(),
+                    flyers: read_guard.flyers.iter().filter(|f| f.expires_at > now).cloned().collect(),
+                })
+            })
+            .collect();
+        RuntimeState { widgets }
+    }
+
+    pub fn display_status(&self) -> DisplayStatus {
+        let settings = self.settings.read().map(|s| s.clone()).unwrap_or_default();
+        DisplayStatus {
+            power: settings.power,
+            brightness: settings.brightness,
+        }
+    }
+
+    /// The last frame sent to the display, encoded as PNG
+    pub fn frame_png(&self) -> Result<Vec<u8>, RenderError> {
+        let frame = self.frame.read().map(|f| f.clone()).unwrap_or_default();
+        let mut buf = Vec::new();
+        image::codecs::png::PngEncoder::new(&mut buf).encode(
+            &frame,
+            frame.width(),
+            frame.height(),
+            image::ColorType::Rgb8,
+        )?;
+        Ok(buf)
+    }
 }
 
 impl Screen {
     pub fn new(width: u32, height: u32, widgets: Vec<WidgetConf>) -> Screen {
+        // Flatten groups, top-level widgets keep their indices and the children of groups are
+        // appended after them, so every widget is still addressable by a single id.
+        let mut confs: Vec<(Option<usize>, WidgetConf)> =
+            widgets.into_iter().map(|w| (None, w)).collect();
+        let mut idx = 0;
+        while idx < confs.len() {
+            if let Widget::Group(g) = &mut confs[idx].1.widget {
+                let children = std::mem::take(&mut g.children);
+                confs.extend(children.into_iter().map(|c| (Some(idx), c)));
+            }
+            idx += 1;
+        }
+
         debug!("Widget lists:");
-        for (idx, w) in widgets.iter().enumerate() {
-            match w.widget {
-                Widget::Solid(_) => debug!("Widget {}: Solid", idx),
-                Widget::Clock(_) => debug!("Widget {}: Clock", idx),
-                Widget::Calendar(_) => debug!("Widget {}: Calendar", idx),
-                Widget::MatrixRain(_) => debug!("Widget {}: MatrixRain", idx),
-                Widget::Gif(_) => debug!("Widget {}: Gif", idx),
-                Widget::Flyer(_) => debug!("Widget {}: Flyer", idx),
-                Widget::Wigwag(_) => debug!("Widget {}: Wigwag", idx),
+        for (idx, (parent, w)) in confs.iter().enumerate() {
+            let kind = w.widget.kind();
+            let name = w.name.as_ref().map(|n| format!(" '{}'", n)).unwrap_or_default();
+            match parent {
+                Some(p) => debug!("Widget {}{}: {} in group {}", idx, name, kind, p),
+                None => debug!("Widget {}{}: {}", idx, name, kind),
             }
         }
 
-        let mut children: Vec<PartTask> = Vec::with_capacity(widgets.len());
+        let names: Vec<Option<String>> = confs.iter().map(|(_, w)| w.name.clone()).collect();
+        let mut children: Vec<PartTask> = Vec::with_capacity(confs.len());
+        let mut roots: Vec<usize> = Vec::new();
+        let mut configs: Vec<Widget> = Vec::with_capacity(confs.len());
 
-        for (idx, mut w) in widgets.into_iter().enumerate() {
+        for (idx, (parent, mut w)) in confs.into_iter().enumerate() {
             let cache = Arc::new(RwLock::new(PartContent {
                 x: w.x,
                 y: w.y,
                 visible: w.visible.unwrap_or(true),
+                opacity: w.opacity.unwrap_or(1.0).clamp(0.0, 1.0),
                 image: None,
+                health: Health::Running,
+                gif_url: None,
+                flyers: Vec::new(),
             }));
 
-            let (sender, receiver) = tokio::sync::mpsc::channel(100); // TODO:
+            let (sender, receiver) = crate::queue::channel(w.queue.clone());
             let mc = cache.clone();
-            let join_handler = tokio::spawn(async move { w.widget.start(mc, idx, receiver).await });
+            let kind = w.widget.kind();
+            configs.push(w.widget.clone());
+            let join_handler = tokio::spawn(async move {
+                let ret = w.widget.start(mc.clone(), idx, receiver).await;
+                if let Ok(mut write_guard) = mc.write() {
+                    write_guard.health = match &ret {
+                        Ok(_) => Health::Stopped,
+                        Err(e) => {
+                            error!("Widget {} failed, error is '{}'.", idx, e);
+                            Health::Failed(e.to_string())
+                        }
+                    };
+                }
+                ret
+            });
 
             let part = PartTask {
+                name: w.name.clone(),
+                kind,
                 content: cache,
                 sender,
                 join_handler,
+                parent,
+                children: Vec::new(),
+                mask: w.mask.as_ref().and_then(|m| Mask::load(m, &names)),
             };
 
             children.push(part);
+            match parent {
+                Some(p) => children[p].children.push(idx),
+                None => roots.push(idx),
+            }
         }
 
-        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        let (sender, receiver) = MessageSender::channel(10);
 
-        let part_senders: Vec<PartSender> = children.iter().map(|c| c.sender.clone()).collect();
-        let part_contents: Vec<PartCache> = children.iter().map(|c| c.content.clone()).collect();
+        let targets: Vec<Target> = children
+            .iter()
+            .zip(configs)
+            .map(|(c, config)| Target {
+                name: c.name.clone(),
+                kind: c.kind,
+                sender: c.sender.clone(),
+                content: c.content.clone(),
+                config: RefCell::new(config),
+            })
+            .collect();
+        let settings: SettingsCache = Default::default();
+        let msg_settings = settings.clone();
+        let scenes: SceneCache = Default::default();
+        let msg_scenes = scenes.clone();
+        let frame_lock: FrameLock = Default::default();
+        let msg_frame_lock = frame_lock.clone();
         tokio::spawn(async move {
-            msg_task(receiver, part_senders, part_contents).await;
+            msg_task(receiver, targets, msg_settings, msg_scenes, msg_frame_lock).await;
         });
 
+        let monitor = ScreenMonitor {
+            parts: Arc::new(
+                children
+                    .iter()
+                    .map(|c| PartInfo {
+                        name: c.name.clone(),
+                        kind: c.kind,
+                        parent: c.parent,
+                        content: c.content.clone(),
+                        queue: c.sender.clone(),
+                    })
+                    .collect(),
+            ),
+            settings: settings.clone(),
+            frame: Arc::new(RwLock::new(ScreenImage::new(width, height))),
+        };
+
         Self {
             width,
             height,
             sender,
             parts: children,
+            roots,
+            settings,
+            scenes,
+            frame_lock,
+            monitor,
+        }
+    }
+
+    pub fn set_compositing(&self, compositing: Compositing) {
+        if let Ok(mut write_guard) = self.settings.write() {
+            write_guard.compositing = compositing;
+        }
+    }
+
+    /// Status of all widgets, in the order of their ids
+    pub fn status(&self) -> Vec<WidgetStatus> {
+        self.monitor.status()
+    }
+
+    pub fn display_status(&self) -> DisplayStatus {
+        self.monitor.display_status()
+    }
+
+    pub fn runtime_state(&self) -> RuntimeState {
+        self.monitor.runtime_state()
+    }
+
+    /// Bring the widgets back to a saved state, applied as a single batch on top of the config
+    pub async fn restore(&self, state: &RuntimeState) -> MessageResult {
+        let messages = state.restore_messages(&self.status(), unix_now());
+        if messages.is_empty() {
+            return Ok(());
+        }
+        self.sender.send(NeoClockMessage::Batch { messages }).await
+    }
+
+    pub fn monitor(&self) -> ScreenMonitor {
+        self.monitor.clone()
+    }
+
+    /// Global brightness of the display, 255 is the full brightness
+    pub fn set_brightness(&self, brightness: u8) {
+        if let Ok(mut write_guard) = self.settings.write() {
+            write_guard.brightness = brightness;
+        }
+    }
+
+    /// Names of the scenes of `Scene` messages
+    pub fn scene_names(&self) -> Vec<String> {
+        self.scenes.read().map(|s| s.keys().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Replace the scenes used by `Scene` messages, scenes can't contain `Scene` messages
+    pub fn set_scenes(&self, scenes: BTreeMap<String, Vec<NeoClockMessage>>) {
+        if let Ok(mut write_guard) = self.scenes.write() {
+            *write_guard = scenes;
+        }
+    }
+
+    /// Replace the post-processing effect chain
+    pub fn set_effects(&self, effects: Vec<Effect>) {
+        if let Ok(mut write_guard) = self.settings.write() {
+            write_guard.effects = effects;
         }
     }
 
@@ -120,30 +445,123 @@ impl Screen {
     }
 
     fn render(&self) -> ScreenImage {
-        let mut screen = PartImage::new(self.width, self.height);
-        fill(&mut screen, BLACK);
+        let _frame = self.frame_lock.read();
+        let settings = self.settings.read().map(|s| s.clone()).unwrap_or_default();
+        if !settings.power {
+            return ScreenImage::new(self.width, self.height);
+        }
+        let mut screen = Canvas::new(settings.compositing, self.width, self.height);
+        // Every part is locked once for the whole frame, widgets only lock their own part so
+        // holding several read locks can't deadlock
+        let guards: Vec<_> = self.parts.iter().map(|p| p.content.read().ok()).collect();
+        let contents: Vec<Option<&PartContent>> = guards.iter().map(|g| g.as_deref()).collect();
         // Blend every visible part image into `screen`
-        for part in self.parts.iter() {
-            if let Ok(read_guard) = part.content.read() {
-                if read_guard.visible {
-                    if let Some(img) = &read_guard.image {
-                        let x = read_guard.x;
-                        let y = read_guard.y;
-                        // Blend `img` into `screen` at position `(x, y)`
-                        for px in 0..img.width() {
-                            for py in 0..img.height() {
-                                if (px + x) < self.width && (py + y) < self.height {
-                                    screen
-                                        .get_pixel_mut(px + x, py + y)
-                                        .blend(img.get_pixel(px, py))
-                                }
-                            }
-                        }
+        for idx in self.roots.iter() {
+            self.render_part(&mut screen, &contents, *idx, 0, 0, 1.0, &[]);
+        }
+        let mut image = screen.finish();
+        for effect in settings.effects.iter() {
+            effect.apply(&mut image);
+        }
+        if settings.brightness < 255 {
+            for p in image.pixels_mut() {
+                for c in p.0.iter_mut() {
+                    *c = (*c as u32 * settings.brightness as u32 / 255) as u8;
+                }
+            }
+        }
+        image
+    }
+
+    /// Blend the part `idx` and all its children into `screen`, `(x, y)`, `opacity` and `masks`
+    /// are inherited from the enclosing groups. `contents` are the contents of all parts, read
+    /// once for the frame.
+    #[allow(clippy::too_many_arguments)]
+    fn render_part<'a>(
+        &self,
+        screen: &mut Canvas,
+        contents: &'a [Option<&'a PartContent>],
+        idx: usize,
+        x: u32,
+        y: u32,
+        opacity: f32,
+        masks: &[&MaskSampler<'a>],
+    ) {
+        let content = match contents[idx] {
+            Some(c) if c.visible => c,
+            _ => return,
+        };
+        let (x, y, opacity) = (x + content.x, y + content.y, opacity * content.opacity);
+        // Children of a translucent group are composited together first, so an overlapped child
+        // doesn't show through the one above it
+        if opacity < 1.0 && !self.parts[idx].children.is_empty() {
+            let mut layer = screen.layer();
+            self.draw_part(&mut layer, contents, idx, x, y, 1.0, masks);
+            screen.blend_layer(&layer, opacity);
+        } else {
+            self.draw_part(screen, contents, idx, x, y, opacity, masks);
+        }
+    }
+
+    /// Blend the part `idx` and its children into `screen`, `(x, y)` and `opacity` are the ones
+    /// of the part.
+    #[allow(clippy::too_many_arguments)]
+    fn draw_part<'a>(
+        &self,
+        screen: &mut Canvas,
+        contents: &'a [Option<&'a PartContent>],
+        idx: usize,
+        x: u32,
+        y: u32,
+        opacity: f32,
+        masks: &[&MaskSampler<'a>],
+    ) {
+        let part = &self.parts[idx];
+        // Part masks borrow the image of the mask part, which may be the part itself
+        let own_mask = match &part.mask {
+            Some(Mask::Part(m)) => {
+                let (mx, my) = self.position(contents, *m);
+                contents[*m]
+                    .and_then(|c| c.image.as_ref())
+                    .map(|img| MaskSampler::from_alpha(img, mx, my))
+            }
+            Some(Mask::Image(img)) => Some(MaskSampler::from_gray(img, x, y)),
+            None => None,
+        };
+        let mut masks = masks.to_vec();
+        masks.extend(own_mask.iter());
+
+        if let Some(img) = contents[idx].and_then(|c| c.image.as_ref()) {
+            // Blend `img` into `screen` at position `(x, y)`
+            for px in 0..img.width() {
+                for py in 0..img.height() {
+                    let (sx, sy) = (px + x, py + y);
+                    if sx < self.width && sy < self.height {
+                        let factor = masks
+                            .iter()
+                            .fold(opacity, |f, m| f * m.sample(sx, sy));
+                        screen.blend(sx, sy, *img.get_pixel(px, py), factor)
                     }
                 }
             }
         }
-        screen.convert()
+        for child in part.children.iter() {
+            self.render_part(screen, contents, *child, x, y, opacity, &masks);
+        }
+    }
+
+    /// Position of the part `idx` on the screen, with the offsets of all enclosing groups
+    fn position(&self, contents: &[Option<&PartContent>], idx: usize) -> (u32, u32) {
+        let mut current = Some(idx);
+        let (mut x, mut y) = (0, 0);
+        while let Some(i) = current {
+            if let Some(c) = contents[i] {
+                x += c.x;
+                y += c.y;
+            }
+            current = self.parts[i].parent;
+        }
+        (x, y)
     }
 
     pub fn render_to<T>(&self, target: &mut T)
@@ -157,10 +575,15 @@ impl Screen {
                 target.set_pixel(x, y, pixel.0[0], pixel.0[1], pixel.0[2]);
             }
         }
+        if let Ok(mut write_guard) = self.monitor.frame.write() {
+            *write_guard = image;
+        }
     }
 
     pub async fn send_str(&self, idx: usize, s: String) -> Result<(), RenderError> {
-        self.parts[idx].sender.send(s).await?;
+        if !self.parts[idx].sender.push(s) {
+            return Err(RenderError::QueueFull(idx));
+        }
         Ok(())
     }
 
@@ -176,9 +599,13 @@ impl Default for Screen {
     fn default() -> Screen {
         let parts = vec![
             WidgetConf {
+                name: None,
                 x: 0,
                 y: 0,
                 visible: Some(true),
+                opacity: None,
+                mask: None,
+                queue: Default::default(),
                 widget: Widget::Solid(SolidWidget {
                     width: DEFAULT_WIDTH,
                     height: DEFAULT_HEIGHT,
@@ -186,41 +613,61 @@ impl Default for Screen {
                 }),
             },
             WidgetConf {
+                name: None,
                 x: 0,
                 y: 0,
                 visible: Some(true),
+                opacity: None,
+                mask: None,
+                queue: Default::default(),
                 widget: Widget::Gif(GifWidget {
                     location: "./robot.gif".to_string(),
                 }),
             },
             WidgetConf {
+                name: None,
                 x: DEFAULT_WIDTH / 2,
                 y: 0,
                 visible: Some(true),
+                opacity: None,
+                mask: None,
+                queue: Default::default(),
                 widget: Widget::Gif(GifWidget {
                     location: Default::default(),
                 }),
             },
             WidgetConf {
+                name: None,
                 x: 0,
                 y: DEFAULT_HEIGHT / 2,
                 visible: Some(true),
+                opacity: None,
+                mask: None,
+                queue: Default::default(),
                 widget: Widget::Gif(GifWidget {
                     location: Default::default(),
                 }),
             },
             WidgetConf {
+                name: None,
                 x: DEFAULT_WIDTH / 2,
                 y: DEFAULT_HEIGHT / 2,
                 visible: Some(true),
+                opacity: None,
+                mask: None,
+                queue: Default::default(),
                 widget: Widget::Gif(GifWidget {
                     location: Default::default(),
                 }),
             },
             WidgetConf {
+                name: None,
                 x: 0,
                 y: 0,
                 visible: Some(true),
+                opacity: None,
+                mask: None,
+                queue: Default::default(),
                 widget: Widget::Clock(ClockWidget {
                     width:DEFAULT_WIDTH,
                     height:DEFAULT_HEIGHT/2,
@@ -235,9 +682,13 @@ impl Default for Screen {
                 }),
             },
             WidgetConf {
+                name: None,
                 x: 0,
                 y: DEFAULT_HEIGHT - 12,
                 visible: Some(true),
+                opacity: None,
+                mask: None,
+                queue: Default::default(),
                 widget: Widget::Calendar(CalendarWidget {
                     width:DEFAULT_WIDTH,
                     height:DEFAULT_HEIGHT/2,
@@ -252,9 +703,13 @@ impl Default for Screen {
                 }),
             },
             WidgetConf {
+                name: None,
                 x: 0,
                 y: 0,
                 visible: Some(true),
+                opacity: None,
+                mask: None,
+                queue: Default::default(),
                 widget: Widget::Flyer(FlyerWidget {
                     width:DEFAULT_WIDTH,
                     height:DEFAULT_HEIGHT,
@@ -277,8 +732,8 @@ impl Default for Screen {
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
-    use image::{Rgba, Rgb};
-    use crate::WidgetConf;
+    use image::{Pixel, Rgba, Rgb};
+    use crate::{WidgetConf, message::{MessageError, MoveMessage, NeoClockMessage, OpacityMessage}};
     use super::*;
 
     #[tokio::test]
@@ -335,9 +790,368 @@ mod tests {
         let img = s.render();
         assert_eq!(img.get_pixel(0, 0), &Rgb::<u8>([255, 0, 0]));
         assert_eq!(img.get_pixel(31, 31), &Rgb::<u8>([255, 0, 0]));
-        assert_eq!(img.get_pixel(32, 31), &Rgb::<u8>([0, 127, 0]));
-        assert_eq!(img.get_pixel(63, 0), &Rgb::<u8>([0, 127, 0]));
-        assert_eq!(img.get_pixel(0, 32), &Rgb::<u8>([0, 0, 127]));
-        assert_eq!(img.get_pixel(63, 63), &Rgb::<u8>([0, 0, 127]));
+        assert_eq!(img.get_pixel(32, 31), &Rgb::<u8>([0, 128, 0]));
+        assert_eq!(img.get_pixel(63, 0), &Rgb::<u8>([0, 128, 0]));
+        assert_eq!(img.get_pixel(0, 32), &Rgb::<u8>([0, 0, 128]));
+        assert_eq!(img.get_pixel(63, 63), &Rgb::<u8>([0, 0, 128]));
+    }
+
+    #[tokio::test]
+    async fn test_group() {
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[
+            {
+                "type": "Group",
+                "name": "card",
+                "x": 16,
+                "y": 16,
+                "children": [
+                    {
+                        "type": "Solid",
+                        "x": 0,
+                        "y": 0,
+                        "width": 8,
+                        "height": 8,
+                        "color": "rgb(255,0,0)"
+                    },
+                    {
+                        "type": "Solid",
+                        "x": 8,
+                        "y": 8,
+                        "width": 8,
+                        "height": 8,
+                        "color": "rgb(0,255,0)"
+                    }
+                ]
+            },
+            {
+                "type": "Solid",
+                "x": 0,
+                "y": 0,
+                "width": 4,
+                "height": 4,
+                "color": "rgb(0,0,255)"
+            }
+        ]"#,
+        )
+        .unwrap();
+        let s = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let status = s.status();
+        assert_eq!(
+            status.iter().map(|w| (w.kind, w.group)).collect::<Vec<_>>(),
+            vec![("Group", None), ("Solid", None), ("Solid", Some(0)), ("Solid", Some(0))]
+        );
+        assert!(status.iter().all(|w| w.health == Health::Running));
+        let img = s.render();
+        assert_eq!(img.get_pixel(0, 0), &Rgb::<u8>([0, 0, 255]));
+        assert_eq!(img.get_pixel(16, 16), &Rgb::<u8>([255, 0, 0]));
+        assert_eq!(img.get_pixel(24, 24), &Rgb::<u8>([0, 255, 0]));
+        assert_eq!(img.get_pixel(8, 8), &Rgb::<u8>([0, 0, 0]));
+
+        // Top-level ids are kept, group children come after them
+        s.sender
+            .send(NeoClockMessage::Move(MoveMessage { id: 0.into(), x: 32, y: 32 }))
+            .await
+            .unwrap();
+        s.sender
+            .send(NeoClockMessage::Opacity(OpacityMessage { id: "card".into(), opacity: 0.5 }))
+            .await
+            .unwrap();
+        s.sender.send(NeoClockMessage::Hide { id: 3.into() }).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let img = s.render();
+        assert_eq!(img.get_pixel(16, 16), &Rgb::<u8>([0, 0, 0]));
+        assert_eq!(img.get_pixel(32, 32), &Rgb::<u8>([128, 0, 0]));
+        assert_eq!(img.get_pixel(40, 40), &Rgb::<u8>([0, 0, 0]));
+
+        s.sender.send(NeoClockMessage::Hide { id: 0.into() }).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let img = s.render();
+        assert_eq!(img.get_pixel(32, 32), &Rgb::<u8>([0, 0, 0]));
+        assert_eq!(img.get_pixel(0, 0), &Rgb::<u8>([0, 0, 255]));
+
+        // The opacity applies to the composited group, the lower child doesn't show through
+        for m in [
+            NeoClockMessage::Show { id: 0.into() },
+            NeoClockMessage::Show { id: 3.into() },
+            NeoClockMessage::Move(MoveMessage { id: 3.into(), x: 0, y: 0 }),
+        ] {
+            s.sender.send(m).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let img = s.render();
+        assert_eq!(img.get_pixel(32, 32), &Rgb::<u8>([0, 128, 0]));
+
+        assert_eq!(
+            s.sender.send(NeoClockMessage::Show { id: 4.into() }).await,
+            Err(MessageError::UnknownWidget { id: 4.into() })
+        );
+        assert_eq!(
+            s.sender
+                .send(NeoClockMessage::Flyer { id: 1.into(), msg: Default::default() })
+                .await,
+            Err(MessageError::WrongWidgetType {
+                id: 1.into(),
+                expected: "Flyer".to_string(),
+                actual: "Solid".to_string()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mask() {
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[
+            {
+                "type": "Solid",
+                "x": 0,
+                "y": 0,
+                "width": 16,
+                "height": 16,
+                "color": "rgb(255,0,0)",
+                "mask": {"part": "circle"}
+            },
+            {
+                "type": "Solid",
+                "name": "circle",
+                "x": 4,
+                "y": 4,
+                "width": 4,
+                "height": 4,
+                "visible": false,
+                "color": "rgba(255,255,255,0.5)"
+            }
+        ]"#,
+        )
+        .unwrap();
+        let s = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let img = s.render();
+        assert_eq!(img.get_pixel(0, 0), &Rgb::<u8>([0, 0, 0]));
+        assert_eq!(img.get_pixel(3, 4), &Rgb::<u8>([0, 0, 0]));
+        assert_eq!(img.get_pixel(4, 4), &Rgb::<u8>([128, 0, 0]));
+        assert_eq!(img.get_pixel(7, 7), &Rgb::<u8>([128, 0, 0]));
+        assert_eq!(img.get_pixel(8, 8), &Rgb::<u8>([0, 0, 0]));
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[
+            {"type": "Solid", "name": "a", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"},
+            {"type": "Solid", "name": "b", "x": 8, "y": 0, "width": 8, "height": 8, "color": "blue", "visible": false}
+        ]"#,
+        )
+        .unwrap();
+        let s = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Nothing is applied if any message in the batch is invalid
+        let batch = |last: NeoClockMessage| NeoClockMessage::Batch {
+            messages: vec![
+                NeoClockMessage::Hide { id: "a".into() },
+                NeoClockMessage::Batch {
+                    messages: vec![NeoClockMessage::Show { id: "b".into() }],
+                },
+                last,
+            ],
+        };
+        assert_eq!(
+            s.sender.send(batch(NeoClockMessage::Brightness { brightness: 10 })).await,
+            Ok(())
+        );
+        let status = s.status();
+        assert!(!status[0].visible);
+        assert!(status[1].visible);
+
+        assert_eq!(
+            s.sender.send(batch(NeoClockMessage::Show { id: "c".into() })).await,
+            Err(MessageError::UnknownWidget { id: "c".into() })
+        );
+        s.sender.send(NeoClockMessage::Show { id: "a".into() }).await.unwrap();
+        assert_eq!(
+            s.sender
+                .send(batch(NeoClockMessage::Flyer { id: "a".into(), msg: Default::default() }))
+                .await,
+            Err(MessageError::WrongWidgetType {
+                id: "a".into(),
+                expected: "Flyer".to_string(),
+                actual: "Solid".to_string()
+            })
+        );
+        assert!(s.status()[0].visible);
+    }
+
+    #[tokio::test]
+    async fn test_queue() {
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[
+            {"type": "Solid", "name": "a", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red",
+                "queue": {"depth": 2}},
+            {"type": "Solid", "name": "b", "x": 8, "y": 0, "width": 8, "height": 8, "color": "blue",
+                "queue": {"depth": 2, "overflow": "drop-newest"}}
+        ]"#,
+        )
+        .unwrap();
+        let s = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Messages in a batch are queued without yielding to the widget tasks
+        let flood = |id: &str| NeoClockMessage::Batch {
+            messages: (0..10)
+                .map(|_| serde_json::from_str(&format!(r#"{{"type":"Solid","id":"{}","color":"green"}}"#, id)).unwrap())
+                .collect(),
+        };
+        assert_eq!(s.sender.send(flood("a")).await, Ok(()));
+        s.sender.send(NeoClockMessage::Hide { id: "a".into() }).await.unwrap();
+        let status = s.status();
+        assert_eq!(status[0].dropped, 8);
+        assert!(!status[0].visible);
+
+        // A batch that doesn't fit in a drop-newest queue is rejected as a whole
+        let mut batch = flood("b");
+        if let NeoClockMessage::Batch { messages } = &mut batch {
+            messages.insert(0, NeoClockMessage::Hide { id: "b".into() });
+        }
+        assert_eq!(s.sender.send(batch).await, Err(MessageError::QueueFull { id: "b".into() }));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let status = s.status();
+        assert_eq!(status[1].dropped, 0);
+        assert!(status[1].visible);
+        let image = s.parts[1].content.read().unwrap().image.clone().unwrap();
+        assert_eq!(*image.get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+    }
+
+    #[tokio::test]
+    async fn test_runtime_state() {
+        let conf = r#"[
+            {"type": "Gif", "name": "gif", "x": 0, "y": 0, "location": "robot.gif"},
+            {"type": "Flyer", "x": 0, "y": 16, "speed": 1},
+            {"type": "Solid", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"}
+        ]"#;
+        let s = Screen::new(64, 64, serde_json::from_str(conf).unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let messages = [
+            r#"{"type":"Gif","id":"gif","url":"cat.gif"}"#,
+            r#"{"type":"Flyer","id":1,"text":"Hello","ttl":60}"#,
+            r#"{"type":"Flyer","id":1,"text":"Never shown","ttl":0}"#,
+            r#"{"type":"Move","id":1,"x":4,"y":20}"#,
+            r#"{"type":"Hide","id":2}"#,
+        ];
+        for m in messages {
+            s.sender.send(serde_json::from_str(m).unwrap()).await.unwrap();
+        }
+        let state = s.runtime_state();
+        assert_eq!(state.widgets[0].gif_url.as_deref(), Some("cat.gif"));
+        let flyers: Vec<&str> = state.widgets[1].flyers.iter().map(|f| f.text.as_str()).collect();
+        assert_eq!(flyers, ["Hello"]);
+
+        // Restored on top of the config by a new screen
+        let restored = Screen::new(64, 64, serde_json::from_str(conf).unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        restored.restore(&state).await.unwrap();
+        assert_eq!(restored.status(), s.status());
+        let restored_state = restored.runtime_state();
+        assert_eq!(restored_state.widgets[0].gif_url.as_deref(), Some("cat.gif"));
+        assert_eq!(restored_state.widgets[1].flyers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_configure() {
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[
+            {"type": "Solid", "name": "bg", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"},
+            {"type": "Group", "x": 0, "y": 0},
+            {"type": "Solid", "name": "fg", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red",
+                "queue": {"overflow": "coalesce-latest"}}
+        ]"#,
+        )
+        .unwrap();
+        let s = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let configure = |id: &str, patch: &str| -> NeoClockMessage {
+            serde_json::from_str(&format!(r#"{{"type":"Configure","id":{},"patch":{}}}"#, id, patch)).unwrap()
+        };
+        let image = || s.parts[0].content.read().unwrap().image.clone().unwrap();
+
+        s.sender.send(configure(r#""bg""#, r#"{"width": 16, "color": "blue"}"#)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(image().dimensions(), (16, 8));
+        assert_eq!(*image().get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+
+        // Patches apply to the current config, not the one in the config file
+        s.sender.send(configure("0", r#"{"height": 4}"#)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(image().dimensions(), (16, 4));
+        assert_eq!(*image().get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+
+        // The color of Solid messages is applied too
+        s.sender.send(serde_json::from_str(r#"{"type":"Solid","id":0,"color":"rgba(0,255,0,0.5)"}"#).unwrap()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*image().get_pixel(0, 0), Rgba([0, 255, 0, 128]));
+
+        assert!(matches!(
+            s.sender.send(configure("0", r#"{"width": null}"#)).await,
+            Err(MessageError::InvalidConfig { .. })
+        ));
+        assert!(matches!(
+            s.sender.send(configure("1", "{}")).await,
+            Err(MessageError::InvalidConfig { .. })
+        ));
+        // Nothing in a batch is applied if a patch is invalid
+        let batch = NeoClockMessage::Batch {
+            messages: vec![NeoClockMessage::Hide { id: 0.into() }, configure("0", r#"{"color": "nope"}"#)],
+        };
+        assert!(s.sender.send(batch).await.is_err());
+        assert!(s.status()[0].visible);
+
+        // Configs are never coalesced with the messages queued after them
+        let mut messages = vec![configure(r#""fg""#, r#"{"width": 2}"#)];
+        messages.extend((0..10).map(|_| serde_json::from_str(r#"{"type":"Solid","id":"fg","color":"blue"}"#).unwrap()));
+        s.sender.send(NeoClockMessage::Batch { messages }).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let image = s.parts[2].content.read().unwrap().image.clone().unwrap();
+        assert_eq!(image.dimensions(), (2, 8));
+        assert_eq!(*image.get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+    }
+
+    #[tokio::test]
+    async fn test_scene() {
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[
+            {"type": "Solid", "name": "bg", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"},
+            {"type": "Solid", "name": "fg", "x": 0, "y": 0, "width": 8, "height": 8, "color": "blue"}
+        ]"#,
+        )
+        .unwrap();
+        let s = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let scenes = serde_json::from_str(
+            r#"{
+            "night": [{"type": "Hide", "id": "bg"}, {"type": "Brightness", "brightness": 10}],
+            "broken": [{"type": "Hide", "id": "fg"}, {"type": "Show", "id": "nope"}],
+            "nested": [{"type": "Scene", "name": "night"}]
+        }"#,
+        )
+        .unwrap();
+        s.set_scenes(scenes);
+        let scene = |name: &str| NeoClockMessage::Scene { name: name.to_string() };
+
+        assert_eq!(s.sender.send(scene("night")).await, Ok(()));
+        assert!(!s.status()[0].visible);
+        assert_eq!(s.display_status().brightness, 10);
+
+        // Scenes are atomic, and can be used in batches
+        assert!(s.sender.send(scene("broken")).await.is_err());
+        assert!(s.status()[1].visible);
+        assert_eq!(
+            s.sender.send(NeoClockMessage::Batch { messages: vec![scene("day")] }).await,
+            Err(MessageError::UnknownScene { name: "day".to_string() })
+        );
+        assert_eq!(
+            s.sender.send(scene("nested")).await,
+            Err(MessageError::UnknownScene { name: "night".to_string() })
+        );
     }
 }
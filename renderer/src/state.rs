@@ -0,0 +1,154 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    message::{FlyerMessage, GifMessage, MoveMessage, NeoClockMessage, OpacityMessage, WidgetId},
+    WidgetStatus,
+};
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A Flyer message that is still on the screen
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FlyerState {
+    pub text: String,
+    /// Seconds since the UNIX epoch
+    pub expires_at: u64,
+}
+
+/// State of a widget changed by messages at runtime
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct WidgetState {
+    pub id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub visible: bool,
+    pub x: u32,
+    pub y: u32,
+    pub opacity: f32,
+    /// The last URL sent to a Gif widget
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gif_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flyers: Vec<FlyerState>,
+}
+
+impl WidgetState {
+    /// Widgets are matched by name, or by id if they have no name, the type must be the same
+    fn matches(&self, w: &WidgetStatus) -> bool {
+        let same = match (&self.name, &w.name) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => self.id == w.id,
+            _ => false,
+        };
+        same && self.kind == w.kind
+    }
+}
+
+/// Runtime state of the screen, saved to restore the screen after a restart
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct RuntimeState {
+    pub widgets: Vec<WidgetState>,
+}
+
+impl RuntimeState {
+    /// Messages that bring `widgets` back to this state, widgets not in the state are left alone
+    /// and Flyer messages expired by `now` are dropped
+    pub fn restore_messages(&self, widgets: &[WidgetStatus], now: u64) -> Vec<NeoClockMessage> {
+        let mut messages = vec![];
+        for w in widgets {
+            let state = match self.widgets.iter().find(|s| s.matches(w)) {
+                Some(s) => s,
+                None => continue,
+            };
+            let id = WidgetId::Index(w.id);
+            messages.push(if state.visible {
+                NeoClockMessage::Show { id: id.clone() }
+            } else {
+                NeoClockMessage::Hide { id: id.clone() }
+            });
+            messages.push(NeoClockMessage::Move(MoveMessage {
+                id: id.clone(),
+                x: state.x,
+                y: state.y,
+            }));
+            messages.push(NeoClockMessage::Opacity(OpacityMessage {
+                id: id.clone(),
+                opacity: state.opacity,
+            }));
+            if let Some(url) = &state.gif_url {
+                messages.push(NeoClockMessage::Gif {
+                    id: id.clone(),
+                    msg: GifMessage { url: url.to_owned() },
+                });
+            }
+            for f in state.flyers.iter().filter(|f| f.expires_at > now) {
+                messages.push(NeoClockMessage::Flyer {
+                    id: id.clone(),
+                    msg: FlyerMessage::new(&f.text, (f.expires_at - now) as u32),
+                });
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Health;
+
+    use super::*;
+
+    fn widget(id: usize, name: Option<&str>, kind: &'static str) -> WidgetStatus {
+        WidgetStatus {
+            id,
+            name: name.map(|n| n.to_owned()),
+            kind,
+            group: None,
+            x: 0,
+            y: 0,
+            visible: true,
+            opacity: 1.0,
+            health: Health::Running,
+            dropped: 0,
+        }
+    }
+
+    #[test]
+    fn test_restore_messages() {
+        let state: RuntimeState = serde_json::from_str(
+            r#"{"widgets": [
+                {"id": 0, "type": "Gif", "visible": false, "x": 1, "y": 2, "opacity": 0.5, "gif_url": "cat.gif"},
+                {"id": 5, "name": "alert", "type": "Flyer", "visible": true, "x": 0, "y": 0, "opacity": 1.0,
+                    "flyers": [{"text": "old", "expires_at": 100}, {"text": "new", "expires_at": 130}]},
+                {"id": 2, "type": "Solid", "visible": false, "x": 0, "y": 0, "opacity": 1.0}
+            ]}"#,
+        )
+        .unwrap();
+        // The Flyer widget has moved, widget 2 is not a Solid widget anymore
+        let widgets = [widget(0, None, "Gif"), widget(1, Some("alert"), "Flyer"), widget(2, None, "Clock")];
+        let messages = state.restore_messages(&widgets, 110);
+        let messages: Vec<String> = messages.iter().map(|m| serde_json::to_string(m).unwrap()).collect();
+        assert_eq!(
+            messages,
+            [
+                r#"{"type":"Hide","id":0}"#,
+                r#"{"type":"Move","id":0,"x":1,"y":2}"#,
+                r#"{"type":"Opacity","id":0,"opacity":0.5}"#,
+                r#"{"type":"Gif","id":0,"url":"cat.gif"}"#,
+                r#"{"type":"Show","id":1}"#,
+                r#"{"type":"Move","id":1,"x":0,"y":0}"#,
+                r#"{"type":"Opacity","id":1,"opacity":1.0}"#,
+                r#"{"type":"Flyer","id":1,"text":"new","ttl":20}"#,
+            ]
+        );
+    }
+}
@@ -2,28 +2,33 @@ use async_trait::async_trait;
 use chrono::{Datelike, Duration, NaiveDate, TimeZone, Timelike, Utc};
 use image::Rgba;
 use log::{debug, info};
-use serde::Deserialize;
-use tokio::time::timeout;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-use crate::{deserialize_pixel, Part, PartCache, PartChannel, PartPixel, RenderError};
+use crate::{
+    deserialize_pixel, message::CalendarMessage, pixel_schema, serialize_pixel, Part, PartCache, PartChannel, PartPixel,
+    Received, RenderError,
+};
 
 use super::font::FontConfig;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 #[serde(default)]
 pub struct CalendarWidget {
     pub width: u32,
     pub height: u32,
-    #[serde(deserialize_with = "deserialize_pixel")]
+    #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+    #[schemars(schema_with = "pixel_schema")]
     pub text_color: PartPixel,
-    #[serde(deserialize_with = "deserialize_pixel")]
+    #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+    #[schemars(schema_with = "pixel_schema")]
     pub background_color: PartPixel,
     #[serde(flatten)]
     pub font_config: FontConfig,
 }
 
 impl CalendarWidget {
-    async fn sleep(&self, channel: &mut PartChannel) -> Option<String> {
+    async fn sleep(&mut self, channel: &mut PartChannel) -> Option<Received<CalendarMessage>> {
         // Sleep until the beginning of the next minute
         let now = Utc::now();
         let n = NaiveDate::from_ymd_opt(now.year(), now.month(), now.day())
@@ -31,10 +36,7 @@ impl CalendarWidget {
             .unwrap();
         let next_min = Utc.from_utc_datetime(&(n + Duration::minutes(1)));
         let d = next_min - now;
-        match timeout(d.to_std().unwrap_or_default(), channel.recv()).await {
-            Ok(v) => v,
-            Err(_) => None,
-        }
+        self.read(channel, d.to_std().unwrap_or_default()).await
     }
 }
 
@@ -59,7 +61,7 @@ impl Part for CalendarWidget {
         mut channel: PartChannel,
     ) -> Result<(), RenderError> {
         info!("CalendarWidget({}) started.", id);
-        let font = self.font_config.load()?;
+        let mut font = self.font_config.load()?;
         loop {
             let now = chrono::Local::now();
             let date_str = now.format("%b %d %a").to_string();
@@ -69,9 +71,10 @@ impl Part for CalendarWidget {
             if let Ok(mut write_guard) = cache.write() {
                 write_guard.image = Some(img);
             }
-            if let Some(s) = self.sleep(&mut channel).await {
-                // TODO: Received a message
-                debug!("Got message '{}'", s);
+            match self.sleep(&mut channel).await {
+                Some(Received::Message(msg)) => debug!("Got message '{:#?}'", msg),
+                Some(Received::Configured) => font = self.font_config.load()?,
+                None => {}
             }
         }
     }
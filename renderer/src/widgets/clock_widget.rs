@@ -2,28 +2,33 @@ use async_trait::async_trait;
 use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use image::Rgba;
 use log::{debug, info};
-use serde::Deserialize;
-use tokio::time::timeout;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-use crate::{deserialize_pixel, PartCache, PartChannel, PartPixel, RenderError};
+use crate::{
+    deserialize_pixel, message::ClockMessage, pixel_schema, serialize_pixel, Part, PartCache, PartChannel, PartPixel,
+    Received, RenderError,
+};
 
 use super::FontConfig;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 #[serde(default)]
 pub struct ClockWidget {
     pub width: u32,
     pub height: u32,
-    #[serde(deserialize_with = "deserialize_pixel")]
+    #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+    #[schemars(schema_with = "pixel_schema")]
     pub text_color: PartPixel,
-    #[serde(deserialize_with = "deserialize_pixel")]
+    #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+    #[schemars(schema_with = "pixel_schema")]
     pub background_color: PartPixel,
     #[serde(flatten)]
     pub font_config: FontConfig,
 }
 
 impl ClockWidget {
-    async fn sleep(&self, channel: &mut PartChannel) -> Option<String> {
+    async fn sleep(&mut self, channel: &mut PartChannel) -> Option<Received<ClockMessage>> {
         // Sleep until the beginning of the next second
         // TODO: Is there any better way to do that?
         let now = Utc::now();
@@ -31,10 +36,7 @@ impl ClockWidget {
         let ns = NaiveDateTime::from_timestamp_opt(ts, 0).unwrap();
         let nt: DateTime<Utc> = Utc.from_utc_datetime(&ns);
         let d = nt - now;
-        match timeout(d.to_std().unwrap(), channel.recv()).await {
-            Ok(v) => v,
-            Err(_) => None,
-        }
+        self.read(channel, d.to_std().unwrap()).await
     }
 }
 
@@ -51,7 +53,7 @@ impl Default for ClockWidget {
 }
 
 #[async_trait]
-impl crate::Part for ClockWidget {
+impl Part for ClockWidget {
     async fn start(
         &mut self,
         cache: PartCache,
@@ -59,7 +61,7 @@ impl crate::Part for ClockWidget {
         mut channel: PartChannel,
     ) -> Result<(), RenderError> {
         info!("ClockWidget({}) started.", id);
-        let font = self.font_config.load()?;
+        let mut font = self.font_config.load()?;
 
         loop {
             let now = chrono::Local::now();
@@ -74,9 +76,10 @@ impl crate::Part for ClockWidget {
             if let Ok(mut write_guard) = cache.write() {
                 write_guard.image = Some(img);
             }
-            if let Some(s) = self.sleep(&mut channel).await {
-                // TODO: Received a message
-                debug!("Got message '{}'", s);
+            match self.sleep(&mut channel).await {
+                Some(Received::Message(msg)) => debug!("Got message '{:#?}'", msg),
+                Some(Received::Configured) => font = self.font_config.load()?,
+                None => {}
             }
         }
     }
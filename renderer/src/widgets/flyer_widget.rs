@@ -3,21 +3,24 @@ use std::time::{Duration, Instant};
 use async_trait::async_trait;
 use image::{GenericImage, Rgba};
 use log::{debug, info};
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 use super::{FontConfig, super::movers::{ScrollIterator, Scrollable}};
 use crate::{
-    deserialize_pixel, Part, PartCache, PartChannel, PartImage, PartPixel, RenderError, widgets::message::FlyerMessage,
+    deserialize_pixel, pixel_schema, serialize_pixel, Part, PartCache, PartChannel, PartImage, PartPixel, Received, RenderError, widgets::message::FlyerMessage,
 };
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 #[serde(default)]
 pub struct FlyerWidget {
     pub width: u32,
     pub height: u32,
-    #[serde(deserialize_with = "deserialize_pixel")]
+    #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+    #[schemars(schema_with = "pixel_schema")]
     pub text_color: PartPixel,
-    #[serde(deserialize_with = "deserialize_pixel")]
+    #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+    #[schemars(schema_with = "pixel_schema")]
     pub background_color: PartPixel,
     #[serde(flatten)]
     pub font_config: FontConfig,
@@ -46,7 +49,7 @@ impl Part for FlyerWidget {
         mut channel: PartChannel,
     ) -> Result<(), RenderError> {
         info!("FlyerWidget({}) started.", id);
-        let font = self.font_config.load()?;
+        let mut font = self.font_config.load()?;
 
         let mut messages: Vec<(FlyerMessage, u32, ScrollIterator<PartPixel>)> = Default::default();
         loop {
@@ -80,18 +83,32 @@ impl Part for FlyerWidget {
                 write_guard.image = img;
             }
 
-            let d = Duration::from_millis((1000 / self.speed) as u64);
-            if let Some(mut msg) = self.try_read::<FlyerMessage>(&mut channel, d).await {
-                if msg.ttl > 0 {
-                    debug!("Got message '{:#?}'", msg);
-                    msg.expiration = Some(Instant::now() + Duration::from_secs(msg.ttl as u64));
-                    let img = font.draw_text(&msg.text, self.text_color, self.background_color);
-                    messages.push((
-                        msg,
-                        img.height(),
-                        img.scroll(self.width, img.height(), -1, 0),
-                    ));
+            let d = Duration::from_millis((1000 / self.speed.max(1)) as u64);
+            let mut msg = match self.read::<FlyerMessage>(&mut channel, d).await {
+                Some(Received::Message(msg)) => msg,
+                Some(Received::Configured) => {
+                    // Redraw the messages on the screen with the new font and colors
+                    font = self.font_config.load()?;
+                    messages = messages
+                        .into_iter()
+                        .map(|(m, _, _)| {
+                            let img = font.draw_text(&m.text, self.text_color, self.background_color);
+                            (m, img.height(), img.scroll(self.width, img.height(), -1, 0))
+                        })
+                        .collect();
+                    continue;
                 }
+                None => continue,
+            };
+            if msg.ttl > 0 {
+                debug!("Got message '{:#?}'", msg);
+                msg.expiration = Some(Instant::now() + Duration::from_secs(msg.ttl as u64));
+                let img = font.draw_text(&msg.text, self.text_color, self.background_color);
+                messages.push((
+                    msg,
+                    img.height(),
+                    img.scroll(self.width, img.height(), -1, 0),
+                ));
             }
         }
     }
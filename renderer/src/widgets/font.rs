@@ -3,13 +3,14 @@ use std::cmp::max;
 use bdf::Glyph;
 use image::{ImageBuffer, Pixel};
 use rusttype::Scale;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 use crate::{PartImage, PartPixel, RenderError};
 
 pub const DEF_FONT: &[u8] = include_bytes!("../../fonts/DejaVuSansMono.ttf");
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 #[serde(default)]
 pub struct FontConfig {
     pub font_path: String,
@@ -3,11 +3,12 @@ use std::{time::Duration, path::PathBuf};
 use async_trait::async_trait;
 use image::{AnimationDecoder, Frame};
 use log::{debug, info};
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-use crate::{Part, PartCache, PartChannel, RenderError, widgets::message::GifMessage};
+use crate::{Part, PartCache, PartChannel, Received, RenderError, widgets::message::GifMessage};
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 pub struct GifWidget {
     // TODO:
     pub location: String,
@@ -65,12 +66,20 @@ impl Part for GifWidget {
                 let delay = ((numer as f64 * 1000f64) / (denom as f64)) as u64;
                 Duration::from_micros(delay)
             };
-            if let Some(msg) = self.try_read::<GifMessage>(&mut channel, d).await {
-                debug!("Gif widget {} got message '{:#?}'", id, msg);
-                if let Ok(f) = self.load_gif(&msg.url).await {
-                    frames = f;
+            match self.read::<GifMessage>(&mut channel, d).await {
+                Some(Received::Message(msg)) => {
+                    debug!("Gif widget {} got message '{:#?}'", id, msg);
+                    if let Ok(f) = self.load_gif(&msg.url).await {
+                        frames = f;
+                        i = 0;
+                    }
+                }
+                Some(Received::Configured) => {
+                    frames = self.load_gif(&self.location).await.unwrap_or_default();
                     i = 0;
+                    continue;
                 }
+                None => {}
             }
 
             i = if frames.is_empty() {
@@ -0,0 +1,34 @@
+use async_trait::async_trait;
+use log::{debug, info};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{Part, PartCache, PartChannel, RenderError, WidgetConf};
+
+/// A container of other widgets.
+///
+/// The group itself draws nothing, its children are positioned relative to the group, and
+/// moving, showing, hiding or fading the group affects all of them at once.
+/// Children get their own ids, which are assigned after all top-level widgets in breadth-first
+/// order, so adding a group never changes the ids of the existing top-level widgets.
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, Serialize)]
+pub struct GroupWidget {
+    #[serde(default, skip_serializing)]
+    pub children: Vec<WidgetConf>,
+}
+
+#[async_trait]
+impl Part for GroupWidget {
+    async fn start(
+        &mut self,
+        _cache: PartCache,
+        id: usize,
+        mut channel: PartChannel,
+    ) -> Result<(), RenderError> {
+        info!("GroupWidget({}) started.", id);
+        while let Some(s) = channel.recv().await {
+            debug!("Group widget {} got message '{}'", id, s);
+        }
+        Ok(())
+    }
+}
@@ -1,20 +1,24 @@
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::{deserialize_pixel, Part, PartCache, PartChannel, PartImage, PartPixel};
+use crate::{
+    deserialize_pixel, pixel_schema, serialize_pixel, Part, PartCache, PartChannel, PartImage, PartPixel, Received,
+};
 use async_trait::async_trait;
 use image::Rgba;
 use log::{debug, info};
 use rand::{Rng, SeedableRng};
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 const COLOR_STEP: u32 = 20;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 #[serde(default)]
 pub struct MatrixRainWidget {
     pub width: u32,
     pub height: u32,
-    #[serde(deserialize_with = "deserialize_pixel")]
+    #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+    #[schemars(schema_with = "pixel_schema")]
     pub color: PartPixel,
     pub speed: u32,
     pub steps: u32,
@@ -81,13 +85,12 @@ impl Part for MatrixRainWidget {
             if let Ok(mut write_guard) = cache.write() {
                 write_guard.image = Some(img);
             }
-            let d = Duration::from_millis((1000 / self.speed) as u64);
-            if let Some(s) = match tokio::time::timeout(d, channel.recv()).await {
-                Ok(s) => s,
-                Err(_) => None,
-            } {
-                // TODO: Received a message
-                debug!("Got message '{}'", s);
+            let d = Duration::from_millis((1000 / self.speed.max(1)) as u64);
+            match self.read::<serde_json::Value>(&mut channel, d).await {
+                Some(Received::Message(msg)) => debug!("Got message '{}'", msg),
+                // The drops may be out of a smaller widget
+                Some(Received::Configured) => lines.clear(),
+                None => {}
             }
         }
     }
@@ -1,69 +1,195 @@
-use std::time::Instant;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
 
-use log::info;
+use log::{info, warn};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::Receiver;
+use thiserror::Error;
+use tokio::sync::{mpsc::{Receiver, Sender}, oneshot};
 
-use crate::{PartCache, PartSender, PartPixel, deserialize_pixel, serialize_pixel};
+use crate::{Effect, PartCache, PartSender, PartPixel, SettingsCache, deserialize_pixel, pixel_schema, screen::{FrameLock, PartContent}, serialize_pixel, state::{unix_now, FlyerState}, Widget};
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// Addresses a widget either by its index or by its name
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum WidgetId {
+    Index(usize),
+    Name(String),
+}
+
+impl Default for WidgetId {
+    fn default() -> Self {
+        WidgetId::Index(0)
+    }
+}
+
+impl From<usize> for WidgetId {
+    fn from(idx: usize) -> Self {
+        WidgetId::Index(idx)
+    }
+}
+
+impl From<&str> for WidgetId {
+    fn from(name: &str) -> Self {
+        WidgetId::Name(name.to_owned())
+    }
+}
+
+impl FromStr for WidgetId {
+    type Err = std::convert::Infallible;
+
+    /// Numbers are indices, everything else is a name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse::<usize>() {
+            Ok(idx) => WidgetId::Index(idx),
+            Err(_) => WidgetId::Name(s.to_owned()),
+        })
+    }
+}
+
+impl Display for WidgetId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WidgetId::Index(idx) => write!(f, "{}", idx),
+            WidgetId::Name(name) => write!(f, "'{}'", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 #[serde(tag = "type")]
 pub enum NeoClockMessage {
-    Show(usize),
-    Hide(usize),
+    Show {
+        id: WidgetId,
+    },
+    Hide {
+        id: WidgetId,
+    },
     Move(MoveMessage),
+    Opacity(OpacityMessage),
+    /// Turn the whole display on or off
+    Power {
+        on: bool,
+    },
+    /// Global brightness of the display, 255 is the full brightness
+    Brightness {
+        brightness: u8,
+    },
+    /// Replace the post-processing effect chain of the whole screen
+    Effects {
+        effects: Vec<Effect>,
+    },
     Solid{
-        id: usize,
+        id: WidgetId,
         #[serde(flatten)]
         msg: SolidMessage,
     },
     Clock{
-        id: usize,
+        id: WidgetId,
         #[serde(flatten)]
         msg: ClockMessage,
     },
     Calendar{
-        id: usize,
+        id: WidgetId,
         #[serde(flatten)]
         msg: CalendarMessage,
     },
     Gif {
-        id: usize,
+        id: WidgetId,
         #[serde(flatten)]
         msg: GifMessage,
     },
     Flyer {
-        id: usize,
+        id: WidgetId,
         #[serde(flatten)]
         msg: FlyerMessage,
     },
+    /// Change the config of any widget but a `Group` while it's running
+    Configure {
+        id: WidgetId,
+        #[serde(flatten)]
+        msg: ConfigureMessage,
+    },
+    /// Apply all messages between two frames, nothing is applied if any of them is invalid.
+    /// Messages to widget tasks, e.g. `Flyer`, are delivered after the layout has changed.
+    Batch {
+        messages: Vec<NeoClockMessage>,
+    },
+    /// Apply the messages of a scene of the config file as a `Batch`
+    Scene {
+        name: String,
+    },
+}
+
+impl NeoClockMessage {
+    /// All message types, see `message_type`
+    pub const TYPES: &'static [&'static str] = &[
+        "Show", "Hide", "Move", "Opacity", "Power", "Brightness", "Effects", "Solid", "Clock", "Calendar", "Gif",
+        "Flyer", "Configure", "Batch", "Scene",
+    ];
+
+    /// The `type` field of the JSON message
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            NeoClockMessage::Show { .. } => "Show",
+            NeoClockMessage::Hide { .. } => "Hide",
+            NeoClockMessage::Move(_) => "Move",
+            NeoClockMessage::Opacity(_) => "Opacity",
+            NeoClockMessage::Power { .. } => "Power",
+            NeoClockMessage::Brightness { .. } => "Brightness",
+            NeoClockMessage::Effects { .. } => "Effects",
+            NeoClockMessage::Solid { .. } => "Solid",
+            NeoClockMessage::Clock { .. } => "Clock",
+            NeoClockMessage::Calendar { .. } => "Calendar",
+            NeoClockMessage::Gif { .. } => "Gif",
+            NeoClockMessage::Flyer { .. } => "Flyer",
+            NeoClockMessage::Configure { .. } => "Configure",
+            NeoClockMessage::Batch { .. } => "Batch",
+            NeoClockMessage::Scene { .. } => "Scene",
+        }
+    }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, Serialize)]
 pub struct MoveMessage {
-    pub id: usize,
+    pub id: WidgetId,
     pub x: u32,
     pub y: u32,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, Serialize)]
+pub struct OpacityMessage {
+    pub id: WidgetId,
+    /// 0.0 is fully transparent, 1.0 is fully opaque
+    pub opacity: f32,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 pub struct SolidMessage {
     #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
-    color: PartPixel,
+    #[schemars(schema_with = "pixel_schema")]
+    pub(crate) color: PartPixel,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct ClockMessage;
+// Braced structs, a flattened unit struct can't be deserialized
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub struct ClockMessage {}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct CalendarMessage;
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub struct CalendarMessage {}
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, Serialize)]
 pub struct GifMessage {
     pub url: String,
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, Serialize)]
 pub struct FlyerMessage {
     pub text: String,
     pub ttl: u32,
@@ -72,64 +198,392 @@ pub struct FlyerMessage {
     pub(crate) expiration: Option<Instant>,
 }
 
-pub async fn msg_task(mut receiver: Receiver<NeoClockMessage>, part_senders: Vec<PartSender>, parts: Vec<PartCache>) {
-    loop {
-        if let Some(msg)= receiver.recv().await {
-            msg_handler(&part_senders, &parts, msg).await;
+impl FlyerMessage {
+    pub fn new(text: &str, ttl: u32) -> Self {
+        Self {
+            text: text.to_owned(),
+            ttl,
+            expiration: None,
         }
     }
 }
 
-pub async fn msg_handler(senders: &[PartSender], parts: &[PartCache], msg: NeoClockMessage) {
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, Serialize)]
+pub struct ConfigureMessage {
+    /// RFC 7386 JSON merge patch of the widget config, in the format of the config file
+    pub patch: serde_json::Value,
+}
+
+/// Sent to the queue of a widget when it's configured, with the whole new config
+#[derive(Deserialize, Serialize)]
+pub(crate) struct PartConfig<W> {
+    pub(crate) configure: W,
+}
+
+/// Why a message was rejected
+#[derive(Clone, Debug, Error, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MessageError {
+    #[error("Invalid message, {message}")]
+    Parse { message: String },
+
+    #[error("Widget {id} doesn't exist.")]
+    UnknownWidget { id: WidgetId },
+
+    #[error("Widget {id} is a {actual} widget, not a {expected} widget.")]
+    WrongWidgetType {
+        id: WidgetId,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Widget {id} can't be configured, {reason}.")]
+    InvalidConfig { id: WidgetId, reason: String },
+
+    #[error("Scene '{name}' doesn't exist.")]
+    UnknownScene { name: String },
+
+    #[error("The queue of widget {id} is full.")]
+    QueueFull { id: WidgetId },
+
+    #[error("Message rejected, {reason}.")]
+    Unauthorized { reason: String },
+
+    #[error("{message_type} messages are not allowed from {origin}.")]
+    Forbidden { message_type: String, origin: String },
+
+    #[error("The screen is not running.")]
+    Closed,
+}
+
+pub type MessageResult = Result<(), MessageError>;
+
+/// A message with optional correlation info, the reply is published to `reply_to`
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+pub struct Request {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    #[serde(flatten)]
+    pub message: NeoClockMessage,
+}
+
+impl Request {
+    /// Parse a request, on failure the correlation info is still extracted if possible so the
+    /// error can be replied
+    #[allow(clippy::result_large_err)]
+    pub fn from_slice(payload: &[u8]) -> Result<Request, (Response, Option<String>)> {
+        serde_json::from_slice::<Request>(payload).map_err(|e| {
+            #[derive(Default, Deserialize)]
+            struct Correlation {
+                request_id: Option<String>,
+                reply_to: Option<String>,
+            }
+            let c: Correlation = serde_json::from_slice(payload).unwrap_or_default();
+            let response = Response::new(
+                c.request_id,
+                Err(MessageError::Parse {
+                    message: e.to_string(),
+                }),
+            );
+            (response, c.reply_to)
+        })
+    }
+}
+
+/// Ack or error reply of a `Request`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<MessageError>,
+}
+
+impl Response {
+    pub fn new(request_id: Option<String>, result: MessageResult) -> Self {
+        Self {
+            request_id,
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+/// Sends messages to the screen and waits for the result
+#[derive(Clone, Debug)]
+pub struct MessageSender(Sender<(NeoClockMessage, oneshot::Sender<MessageResult>)>);
+
+impl MessageSender {
+    pub(crate) fn channel(size: usize) -> (Self, Receiver<(NeoClockMessage, oneshot::Sender<MessageResult>)>) {
+        let (sender, receiver) = tokio::sync::mpsc::channel(size);
+        (Self(sender), receiver)
+    }
+
+    pub async fn send(&self, msg: NeoClockMessage) -> MessageResult {
+        let (reply, result) = oneshot::channel();
+        self.0.send((msg, reply)).await.map_err(|_| MessageError::Closed)?;
+        result.await.map_err(|_| MessageError::Closed)?
+    }
+}
+
+/// Everything the message handler needs to know about a part
+pub(crate) struct Target {
+    pub(crate) name: Option<String>,
+    pub(crate) kind: &'static str,
+    pub(crate) sender: PartSender,
+    pub(crate) content: PartCache,
+    /// The current config of the widget, patched by `Configure` messages
+    pub(crate) config: RefCell<Widget>,
+}
+
+/// Named lists of messages, see `NeoClockMessage::Scene`
+pub(crate) type SceneCache = Arc<RwLock<BTreeMap<String, Vec<NeoClockMessage>>>>;
+
+pub(crate) async fn msg_task(mut receiver: Receiver<(NeoClockMessage, oneshot::Sender<MessageResult>)>, targets: Vec<Target>, settings: SettingsCache, scenes: SceneCache, frame: FrameLock) {
+    while let Some((msg, reply)) = receiver.recv().await {
+        let result = msg_handler(&targets, &settings, &scenes, &frame, msg);
+        if let Err(e) = &result {
+            warn!("Message rejected, {}", e);
+        }
+        reply.send(result).unwrap_or_default();
+    }
+}
+
+fn get_target<'a>(targets: &'a [Target], id: WidgetId, expected: Option<&str>) -> Result<&'a Target, MessageError> {
+    let target = match &id {
+        WidgetId::Index(idx) => targets.get(*idx),
+        WidgetId::Name(name) => targets.iter().find(|t| t.name.as_ref() == Some(name)),
+    }
+    .ok_or_else(|| MessageError::UnknownWidget { id: id.clone() })?;
+    match expected {
+        Some(kind) if kind != target.kind => Err(MessageError::WrongWidgetType {
+            id,
+            expected: kind.to_owned(),
+            actual: target.kind.to_owned(),
+        }),
+        _ => Ok(target),
+    }
+}
+
+/// Queue a message for the widget task, never waits for the widget
+fn send_to<T: Serialize + std::fmt::Debug>(targets: &[Target], id: WidgetId, kind: &str, m: &T) -> MessageResult {
+    info!("Sending {} message '{:#?}' to widget {}", kind, m, id);
+    let target = get_target(targets, id.clone(), Some(kind))?;
+    if !target.sender.push(serde_json::to_string(m).unwrap()) {
+        return Err(MessageError::QueueFull { id });
+    }
+    Ok(())
+}
+
+/// The config of the target with the patch of a `Configure` message applied
+fn patched<'a>(targets: &'a [Target], id: WidgetId, m: &ConfigureMessage) -> Result<(&'a Target, Widget), MessageError> {
+    let target = get_target(targets, id.clone(), None)?;
+    let widget = target
+        .config
+        .borrow()
+        .patched(&m.patch)
+        .map_err(|reason| MessageError::InvalidConfig { id, reason })?;
+    Ok((target, widget))
+}
+
+fn configure(targets: &[Target], id: WidgetId, m: &ConfigureMessage) -> MessageResult {
+    info!("Configuring widget {} with '{}'", id, m.patch);
+    let (target, widget) = patched(targets, id.clone(), m)?;
+    let json = serde_json::to_string(&PartConfig { configure: &widget }).unwrap();
+    if !target.sender.push_config(json) {
+        return Err(MessageError::QueueFull { id });
+    }
+    *target.config.borrow_mut() = widget;
+    Ok(())
+}
+
+fn update<F: FnOnce(&mut PartContent)>(targets: &[Target], id: WidgetId, f: F) -> MessageResult {
+    let target = get_target(targets, id, None)?;
+    if let Ok(mut write_guard) = target.content.write() {
+        f(&mut write_guard);
+    }
+    Ok(())
+}
+
+/// Check the message can be applied, without applying it
+fn validate(targets: &[Target], msg: &NeoClockMessage) -> MessageResult {
+    let (id, kind) = match msg {
+        NeoClockMessage::Gif { id, .. } => (id, Some("Gif")),
+        NeoClockMessage::Flyer { id, .. } => (id, Some("Flyer")),
+        NeoClockMessage::Solid { id, .. } => (id, Some("Solid")),
+        NeoClockMessage::Clock { id, .. } => (id, Some("Clock")),
+        NeoClockMessage::Calendar { id, .. } => (id, Some("Calendar")),
+        NeoClockMessage::Configure { id, msg } => return patched(targets, id.clone(), msg).map(|_| ()),
+        NeoClockMessage::Show { id }
+        | NeoClockMessage::Hide { id }
+        | NeoClockMessage::Move(MoveMessage { id, .. })
+        | NeoClockMessage::Opacity(OpacityMessage { id, .. }) => (id, None),
+        NeoClockMessage::Power { .. } | NeoClockMessage::Brightness { .. } | NeoClockMessage::Effects { .. } => {
+            return Ok(())
+        }
+        NeoClockMessage::Batch { messages } => return messages.iter().try_for_each(|m| validate(targets, m)),
+        // Replaced by its messages before validation, see `flatten`
+        NeoClockMessage::Scene { .. } => return Ok(()),
+    };
+    get_target(targets, id.clone(), kind).map(|_| ())
+}
+
+fn is_widget_message(msg: &NeoClockMessage) -> bool {
+    matches!(
+        msg,
+        NeoClockMessage::Gif { .. }
+            | NeoClockMessage::Flyer { .. }
+            | NeoClockMessage::Solid { .. }
+            | NeoClockMessage::Clock { .. }
+            | NeoClockMessage::Calendar { .. }
+            | NeoClockMessage::Configure { .. }
+    )
+}
+
+/// Check every widget can queue its messages of the batch, only the message handler queues
+/// messages and the widgets only take them, so there is still room when they are delivered
+fn reserve(targets: &[Target], messages: &[NeoClockMessage]) -> MessageResult {
+    let mut counts = vec![0; targets.len()];
+    for m in messages {
+        let id = match m {
+            NeoClockMessage::Gif { id, .. }
+            | NeoClockMessage::Flyer { id, .. }
+            | NeoClockMessage::Solid { id, .. }
+            | NeoClockMessage::Clock { id, .. }
+            | NeoClockMessage::Calendar { id, .. } => id,
+            // Configs are always queued, see `PartSender::push_config`
+            _ => continue,
+        };
+        let target = get_target(targets, id.clone(), None)?;
+        let idx = targets.iter().position(|t| std::ptr::eq(t, target)).unwrap_or_default();
+        counts[idx] += 1;
+        if !target.sender.has_room(counts[idx]) {
+            return Err(MessageError::QueueFull { id: id.clone() });
+        }
+    }
+    Ok(())
+}
+
+/// Nested batches and scenes are applied as a part of the outer batch, `scenes` is `None` for the
+/// messages of a scene as scenes can't contain scenes
+fn flatten(scenes: Option<&SceneCache>, messages: Vec<NeoClockMessage>, out: &mut Vec<NeoClockMessage>) -> MessageResult {
+    for m in messages {
+        match m {
+            NeoClockMessage::Batch { messages } => flatten(scenes, messages, out)?,
+            NeoClockMessage::Scene { name } => {
+                let messages = scenes
+                    .and_then(|s| s.read().ok()?.get(&name).cloned())
+                    .ok_or(MessageError::UnknownScene { name })?;
+                flatten(None, messages, out)?;
+            }
+            m => out.push(m),
+        }
+    }
+    Ok(())
+}
+
+/// Deliver a message to the task of its widget
+fn deliver(targets: &[Target], msg: NeoClockMessage) -> MessageResult {
     match msg {
-        NeoClockMessage::Gif{id, msg: m} => {
-            info!("Sending Gif message '{:#?}' to widget {}", m, id);
-            senders[id].send(serde_json::to_string(&m).unwrap()).await.unwrap_or_default();
-        },
-        NeoClockMessage::Flyer{id, msg: m} => {
-            info!("Sending Flyer message '{:#?}' to widget {}", m, id);
-            senders[id].send(serde_json::to_string(&m).unwrap()).await.unwrap_or_default();
-        }
-        NeoClockMessage::Solid{id, msg: m} => {
-            info!("Sending Solid message '{:#?}' to widget {}", m, id);
-            senders[id].send(serde_json::to_string(&m).unwrap()).await.unwrap_or_default();
-        }
-        NeoClockMessage::Clock{id, msg: m} => {
-            info!("Sending Clock message '{:#?}' to widget {}", m, id);
-            senders[id].send(serde_json::to_string(&m).unwrap()).await.unwrap_or_default();
-        }
-        NeoClockMessage::Calendar{id, msg: m} => {
-            info!("Sending Calendar message '{:#?}' to widget {}", m, id);
-            senders[id].send(serde_json::to_string(&m).unwrap()).await.unwrap_or_default();
-        }
-        NeoClockMessage::Show(id) => {
-            if id < parts.len() {
-                if let Ok(mut write_guard) = parts[id].write() {
-                    write_guard.visible = true;
+        NeoClockMessage::Gif { id, msg: m } => {
+            send_to(targets, id.clone(), "Gif", &m)?;
+            update(targets, id, |c| c.gif_url = Some(m.url))
+        }
+        NeoClockMessage::Flyer { id, msg: m } => {
+            send_to(targets, id.clone(), "Flyer", &m)?;
+            // Same expiration as in the widget, `ttl` 0 messages are never shown
+            let now = unix_now();
+            update(targets, id, |c| {
+                c.flyers.retain(|f| f.expires_at > now);
+                if m.ttl > 0 {
+                    c.flyers.push(FlyerState {
+                        text: m.text,
+                        expires_at: now + m.ttl as u64,
+                    });
                 }
-            }    
+            })
+        }
+        NeoClockMessage::Solid { id, msg: m } => send_to(targets, id, "Solid", &m),
+        NeoClockMessage::Clock { id, msg: m } => send_to(targets, id, "Clock", &m),
+        NeoClockMessage::Calendar { id, msg: m } => send_to(targets, id, "Calendar", &m),
+        NeoClockMessage::Configure { id, msg: m } => configure(targets, id, &m),
+        // Not a widget message, see `is_widget_message`
+        _ => Ok(()),
+    }
+}
+
+/// Apply a message that changes the screen state directly
+fn apply(targets: &[Target], settings: &SettingsCache, msg: NeoClockMessage) -> MessageResult {
+    match msg {
+        NeoClockMessage::Show { id } => update(targets, id, |c| c.visible = true),
+        NeoClockMessage::Hide { id } => update(targets, id, |c| c.visible = false),
+        NeoClockMessage::Move(MoveMessage { id, x, y }) => update(targets, id, |c| {
+            c.x = x;
+            c.y = y;
+        }),
+        NeoClockMessage::Opacity(OpacityMessage { id, opacity }) => {
+            update(targets, id, |c| c.opacity = opacity.clamp(0.0, 1.0))
+        }
+        NeoClockMessage::Power { on } => {
+            info!("Turning display {}", if on { "on" } else { "off" });
+            if let Ok(mut write_guard) = settings.write() {
+                write_guard.power = on;
+            }
+            Ok(())
         },
-        NeoClockMessage::Hide(id) => {
-            if id < parts.len() {
-                if let Ok(mut write_guard) = parts[id].write() {
-                    write_guard.visible = false;
-                }
-            }    
+        NeoClockMessage::Brightness { brightness } => {
+            info!("Setting brightness to {}", brightness);
+            if let Ok(mut write_guard) = settings.write() {
+                write_guard.brightness = brightness;
+            }
+            Ok(())
         },
-        NeoClockMessage::Move(MoveMessage { id, x, y }) => {
-            if id < parts.len() {
-                if let Ok(mut write_guard) = parts[id].write() {
-                    write_guard.x = x;
-                    write_guard.y = y;
-                }
-            }    
+        NeoClockMessage::Effects { effects } => {
+            info!("Switching effect chain to '{:?}'", effects);
+            if let Ok(mut write_guard) = settings.write() {
+                write_guard.effects = effects;
+            }
+            Ok(())
         },
-    };
+        // Widget messages and batches are handled by `msg_handler`
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn msg_handler(targets: &[Target], settings: &SettingsCache, scenes: &SceneCache, frame: &FrameLock, msg: NeoClockMessage) -> MessageResult {
+    match msg {
+        msg @ (NeoClockMessage::Batch { .. } | NeoClockMessage::Scene { .. }) => {
+            let mut flat = Vec::new();
+            flatten(Some(scenes), vec![msg], &mut flat)?;
+            info!("Applying a batch of {} messages", flat.len());
+            for m in flat.iter() {
+                validate(targets, m)?;
+            }
+            // No frame can be rendered in the middle of the batch, the layout changes and the
+            // widget messages are applied together and in order
+            let _guard = frame.write();
+            reserve(targets, &flat)?;
+            for m in flat {
+                if is_widget_message(&m) {
+                    deliver(targets, m)?;
+                } else {
+                    apply(targets, settings, m)?;
+                }
+            }
+            Ok(())
+        }
+        msg if is_widget_message(&msg) => deliver(targets, msg),
+        msg => apply(targets, settings, msg),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::NeoClockMessage;
+    use crate::Effect;
+    use super::{MessageError, NeoClockMessage, Request, Response, WidgetId};
 
     #[test]
     fn test_msg() {
@@ -137,7 +591,7 @@ mod tests {
         let msg = serde_json::from_str::<NeoClockMessage>(s).unwrap();
         match msg {
             NeoClockMessage::Flyer{id, msg} => {
-                assert_eq!(id, 10);
+                assert_eq!(id, WidgetId::Index(10));
                 assert_eq!(msg.text, "Hahaha");
                 assert_eq!(msg.ttl, 10);
             },
@@ -146,4 +600,112 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_visibility_msg() {
+        let s = r#"{"type":"Hide","id":3}"#;
+        let msg = serde_json::from_str::<NeoClockMessage>(s).unwrap();
+        assert!(matches!(msg, NeoClockMessage::Hide { id: WidgetId::Index(3) }));
+        assert_eq!(serde_json::to_string(&msg).unwrap(), s);
+
+        let s = r#"{"type":"Opacity","id":8,"opacity":0.25}"#;
+        match serde_json::from_str::<NeoClockMessage>(s).unwrap() {
+            NeoClockMessage::Opacity(m) => {
+                assert_eq!(m.id, WidgetId::Index(8));
+                assert_eq!(m.opacity, 0.25);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_effects_msg() {
+        let s = r#"{"type":"Effects","effects":[{"type":"Invert"},{"type":"BoxBlur","radius":1}]}"#;
+        match serde_json::from_str::<NeoClockMessage>(s).unwrap() {
+            NeoClockMessage::Effects { effects } => {
+                assert_eq!(effects, vec![Effect::Invert, Effect::BoxBlur { radius: 1 }]);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_request() {
+        let s = r#"{"type":"Flyer","id":7,"text":"Hi","ttl":5,"request_id":"42","reply_to":"replies"}"#;
+        let req = Request::from_slice(s.as_bytes()).unwrap();
+        assert_eq!(req.request_id.as_deref(), Some("42"));
+        assert_eq!(req.reply_to.as_deref(), Some("replies"));
+        assert!(matches!(req.message, NeoClockMessage::Flyer { id: WidgetId::Index(7), .. }));
+
+        // Correlation info is optional
+        let req = Request::from_slice(br#"{"type":"Show","id":1}"#).unwrap();
+        assert!(req.request_id.is_none());
+        assert!(matches!(req.message, NeoClockMessage::Show { id: WidgetId::Index(1) }));
+
+        let (resp, reply_to) =
+            Request::from_slice(br#"{"type":"Shw","id":1,"request_id":"a","reply_to":"r"}"#)
+                .unwrap_err();
+        assert_eq!(reply_to.as_deref(), Some("r"));
+        assert_eq!(resp.request_id.as_deref(), Some("a"));
+        assert!(!resp.ok);
+        assert!(matches!(resp.error, Some(MessageError::Parse { .. })));
+
+        let resp = Response::new(Some("b".to_string()), Err(MessageError::UnknownWidget { id: 9.into() }));
+        assert_eq!(
+            serde_json::to_string(&resp).unwrap(),
+            r#"{"request_id":"b","ok":false,"error":{"kind":"unknown_widget","id":9}}"#
+        );
+        assert_eq!(serde_json::to_string(&Response::new(None, Ok(()))).unwrap(), r#"{"ok":true}"#);
+    }
+
+    #[test]
+    fn test_widget_name() {
+        let s = r#"{"type":"Move","id":"weather","x":1,"y":2}"#;
+        match serde_json::from_str::<NeoClockMessage>(s).unwrap() {
+            NeoClockMessage::Move(m) => assert_eq!(m.id, WidgetId::Name("weather".to_string())),
+            _ => panic!(),
+        }
+        assert_eq!("3".parse::<WidgetId>().unwrap(), WidgetId::Index(3));
+        assert_eq!("clock".parse::<WidgetId>().unwrap(), WidgetId::from("clock"));
+    }
+
+    #[test]
+    fn test_batch_msg() {
+        let s = r#"{"type":"Batch","messages":[{"type":"Hide","id":0},{"type":"Batch","messages":[{"type":"Show","id":"alert"}]}]}"#;
+        let msg = serde_json::from_str::<NeoClockMessage>(s).unwrap();
+        match &msg {
+            NeoClockMessage::Batch { messages } => {
+                assert_eq!(messages.len(), 2);
+                assert!(matches!(messages[1], NeoClockMessage::Batch { .. }));
+            }
+            _ => panic!(),
+        }
+        assert_eq!(serde_json::to_string(&msg).unwrap(), s);
+    }
+
+    #[test]
+    fn test_message_type() {
+        let messages = [
+            r#"{"type":"Show","id":0}"#,
+            r#"{"type":"Hide","id":0}"#,
+            r#"{"type":"Move","id":0,"x":0,"y":0}"#,
+            r#"{"type":"Opacity","id":0,"opacity":1.0}"#,
+            r#"{"type":"Power","on":true}"#,
+            r#"{"type":"Brightness","brightness":1}"#,
+            r#"{"type":"Effects","effects":[]}"#,
+            r#"{"type":"Solid","id":0,"color":"red"}"#,
+            r#"{"type":"Clock","id":0}"#,
+            r#"{"type":"Calendar","id":0}"#,
+            r#"{"type":"Gif","id":0,"url":""}"#,
+            r#"{"type":"Flyer","id":0,"text":"","ttl":0}"#,
+            r#"{"type":"Configure","id":0,"patch":{}}"#,
+            r#"{"type":"Batch","messages":[]}"#,
+            r#"{"type":"Scene","name":"night"}"#,
+        ];
+        let types: Vec<_> = messages
+            .iter()
+            .map(|m| serde_json::from_str::<NeoClockMessage>(m).unwrap().message_type())
+            .collect();
+        assert_eq!(types, NeoClockMessage::TYPES);
+    }
+}
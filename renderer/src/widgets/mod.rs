@@ -3,6 +3,7 @@ mod clock_widget;
 mod flyer_widget;
 mod font;
 mod gif_widget;
+mod group_widget;
 mod matrix_rain_widget;
 mod solid_widget;
 mod wigwag_widget;
@@ -10,18 +11,21 @@ pub mod message;
 
 use crate::{Part, PartCache, PartChannel, RenderError};
 use async_trait::async_trait;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 pub use calendar_widget::CalendarWidget;
 pub use clock_widget::ClockWidget;
 pub use flyer_widget::FlyerWidget;
 pub use font::FontConfig;
 pub use gif_widget::GifWidget;
+pub use group_widget::GroupWidget;
 pub use matrix_rain_widget::MatrixRainWidget;
 pub use solid_widget::SolidWidget;
 pub use wigwag_widget::WigwagWidget;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 #[serde(tag = "type")]
 pub enum Widget {
     Solid(SolidWidget),
@@ -31,6 +35,111 @@ pub enum Widget {
     Gif(GifWidget),
     Flyer(FlyerWidget),
     Wigwag(WigwagWidget),
+    Group(GroupWidget),
+}
+
+impl Widget {
+    /// Name of the widget type, same as the `type` tag in the config file
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Solid(_) => "Solid",
+            Self::Clock(_) => "Clock",
+            Self::Calendar(_) => "Calendar",
+            Self::MatrixRain(_) => "MatrixRain",
+            Self::Gif(_) => "Gif",
+            Self::Flyer(_) => "Flyer",
+            Self::Wigwag(_) => "Wigwag",
+            Self::Group(_) => "Group",
+        }
+    }
+
+    fn font_config(&self) -> Option<&FontConfig> {
+        match self {
+            Self::Clock(w) => Some(&w.font_config),
+            Self::Calendar(w) => Some(&w.font_config),
+            Self::Flyer(w) => Some(&w.font_config),
+            Self::Wigwag(w) => Some(&w.font_config),
+            _ => None,
+        }
+    }
+
+    /// Values the widget tasks can't run with, e.g. a `speed` of 0 is a division by zero
+    fn check(&self) -> Result<(), String> {
+        let speed = match self {
+            Self::Flyer(w) => Some(w.speed),
+            Self::MatrixRain(w) => Some(w.speed),
+            Self::Wigwag(w) => Some(w.speed),
+            _ => None,
+        };
+        if speed == Some(0) {
+            return Err("speed must be greater than 0".to_string());
+        }
+        if let Self::MatrixRain(w) = self {
+            if w.width == 0 {
+                return Err("width must be greater than 0".to_string());
+            }
+            // The drops are 15 pixels long
+            if w.steps < 15 {
+                return Err("steps must be at least 15".to_string());
+            }
+        }
+        if let Some(font) = self.font_config() {
+            for (name, value) in [
+                ("font_height", font.font_height),
+                ("font_scale_x", font.font_scale_x),
+                ("font_scale_y", font.font_scale_y),
+            ] {
+                if !value.is_finite() || value <= 0.0 {
+                    return Err(format!("{} must be greater than 0", name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The config with a RFC 7386 JSON merge patch applied, the widget type can't be changed, the
+    /// values must be in range and the font must load, so the widget task never gets a config it
+    /// can't use
+    pub(crate) fn patched(&self, patch: &Value) -> Result<Widget, String> {
+        if let Self::Group(_) = self {
+            return Err("Group widgets have nothing to configure".to_string());
+        }
+        let mut value = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        merge_patch(&mut value, patch);
+        let widget: Widget = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        if widget.kind() != self.kind() {
+            return Err("the widget type can't be changed".to_string());
+        }
+        widget.check()?;
+        if let Some(font) = widget.font_config() {
+            font.load().map_err(|e| e.to_string())?;
+        }
+        Ok(widget)
+    }
+}
+
+/// RFC 7386, `null` removes a member and objects are merged recursively, anything else replaces
+/// the target
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(p) => p,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    if let Value::Object(t) = target {
+        for (k, v) in patch {
+            if v.is_null() {
+                t.remove(k);
+            } else {
+                merge_patch(t.entry(k.as_str()).or_insert(Value::Null), v);
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -49,6 +158,57 @@ impl Part for Widget {
             Self::Gif(s) => s.start(cache, id, channel).await,
             Self::Flyer(s) => s.start(cache, id, channel).await,
             Self::Wigwag(s) => s.start(cache, id, channel).await,
+            Self::Group(s) => s.start(cache, id, channel).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_merge_patch() {
+        // The example of RFC 7386
+        let mut target = json!({"title": "Goodbye!", "author": {"givenName": "John", "familyName": "Doe"}, "tags": ["example", "sample"], "content": "This will be unchanged"});
+        let patch = json!({"title": "Hello!", "phoneNumber": "+01-123-456-7890", "author": {"familyName": null}, "tags": ["example"]});
+        merge_patch(&mut target, &patch);
+        assert_eq!(
+            target,
+            json!({"title": "Hello!", "author": {"givenName": "John"}, "tags": ["example"], "content": "This will be unchanged", "phoneNumber": "+01-123-456-7890"})
+        );
+        merge_patch(&mut target, &json!(["a"]));
+        assert_eq!(target, json!(["a"]));
+        merge_patch(&mut target, &json!({"a": {"b": "c"}}));
+        assert_eq!(target, json!({"a": {"b": "c"}}));
+    }
+
+    #[test]
+    fn test_patched() {
+        let widget: Widget = serde_json::from_value(json!({"type": "Clock", "width": 10, "height": 5, "text_color": "red"})).unwrap();
+        match widget.patched(&json!({"text_color": "rgba(0, 0, 255, 0.5)", "font_height": 8.0})).unwrap() {
+            Widget::Clock(c) => {
+                assert_eq!(c.width, 10);
+                assert_eq!(c.text_color.0, [0, 0, 255, 128]);
+                assert_eq!(c.font_config.font_height, 8.0);
+            }
+            _ => panic!(),
+        }
+        assert!(widget.patched(&json!({"type": "Calendar"})).is_err());
+        assert!(widget.patched(&json!({"text_color": "not a color"})).is_err());
+        assert!(widget.patched(&json!({"font_path": "missing.ttf"})).is_err());
+        assert!(widget.patched(&json!({"font_height": 0.0})).is_err());
+        for kind in ["Flyer", "MatrixRain", "Wigwag"] {
+            let widget: Widget = serde_json::from_value(json!({"type": kind})).unwrap();
+            assert!(widget.patched(&json!({"speed": 10})).is_ok());
+            assert_eq!(widget.patched(&json!({"speed": 0})).unwrap_err(), "speed must be greater than 0");
         }
+        let rain: Widget = serde_json::from_value(json!({"type": "MatrixRain"})).unwrap();
+        assert!(rain.patched(&json!({"steps": 0})).is_err());
+        assert!(rain.patched(&json!({"width": 0})).is_err());
+        let group: Widget = serde_json::from_value(json!({"type": "Group"})).unwrap();
+        assert!(group.patched(&json!({})).is_err());
     }
 }
@@ -1,18 +1,20 @@
 use std::time::Duration;
 
 use crate::{
-    deserialize_pixel, fill, message::SolidMessage, Part, PartCache, PartChannel, PartImage,
-    PartPixel,
+    deserialize_pixel, pixel_schema, serialize_pixel, fill, message::SolidMessage, Part, PartCache, PartChannel, PartImage,
+    PartPixel, Received,
 };
 use async_trait::async_trait;
 use log::{debug, info};
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 pub struct SolidWidget {
     pub width: u32,
     pub height: u32,
-    #[serde(deserialize_with = "deserialize_pixel")]
+    #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+    #[schemars(schema_with = "pixel_schema")]
     pub color: PartPixel,
 }
 
@@ -32,11 +34,16 @@ impl Part for SolidWidget {
                 write_guard.image = Some(img);
             }
 
-            if let Some(msg) = self
-                .try_read::<SolidMessage>(&mut channel, Duration::from_secs(86400))
+            match self
+                .read::<SolidMessage>(&mut channel, Duration::from_secs(86400))
                 .await
             {
-                debug!("Solid widget {} got message '{:#?}'", id, msg);
+                Some(Received::Message(msg)) => {
+                    debug!("Solid widget {} got message '{:#?}'", id, msg);
+                    self.color = msg.color;
+                }
+                Some(Received::Configured) => debug!("Solid widget {} configured", id),
+                None => {}
             }
         }
     }
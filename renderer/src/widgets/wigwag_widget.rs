@@ -3,23 +3,26 @@ use std::time::Duration;
 use async_trait::async_trait;
 use image::Rgba;
 use log::{debug, info};
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 use super::FontConfig;
 use crate::{
-    deserialize_pixel, movers::Wigwagable, Part, PartCache, PartChannel, PartPixel, RenderError,
+    deserialize_pixel, pixel_schema, serialize_pixel, movers::Wigwagable, Part, PartCache, PartChannel, PartPixel, Received,
+    RenderError,
 };
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
 #[serde(default)]
 pub struct WigwagWidget {
     pub width: u32,
     pub height: u32,
-    // TODO:
     pub text: String,
-    #[serde(deserialize_with = "deserialize_pixel")]
+    #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+    #[schemars(schema_with = "pixel_schema")]
     pub text_color: PartPixel,
-    #[serde(deserialize_with = "deserialize_pixel")]
+    #[serde(deserialize_with = "deserialize_pixel", serialize_with = "serialize_pixel")]
+    #[schemars(schema_with = "pixel_schema")]
     pub background_color: PartPixel,
     #[serde(flatten)]
     pub font_config: FontConfig,
@@ -49,21 +52,20 @@ impl Part for WigwagWidget {
         mut channel: PartChannel,
     ) -> Result<(), RenderError> {
         info!("WigwagWidget({}) started.", id);
-        let font = self.font_config.load()?;
-
-        let text_img = font.draw_text(&self.text, self.text_color, self.background_color);
-        let mut f = text_img.wigwag(self.width, self.height);
         loop {
-            if let Ok(mut write_guard) = cache.write() {
-                write_guard.image = Some(f.next().unwrap());
-            }
-            let d = Duration::from_millis((1000 / self.speed) as u64);
-            if let Some(s) = match tokio::time::timeout(d, channel.recv()).await {
-                Ok(s) => s,
-                Err(_) => None,
-            } {
-                // TODO: Received a message
-                debug!("Got message '{}'", s);
+            let font = self.font_config.load()?;
+            let text_img = font.draw_text(&self.text, self.text_color, self.background_color);
+            let mut f = text_img.wigwag(self.width, self.height);
+            loop {
+                if let Ok(mut write_guard) = cache.write() {
+                    write_guard.image = Some(f.next().unwrap());
+                }
+                let d = Duration::from_millis((1000 / self.speed.max(1)) as u64);
+                match self.read::<serde_json::Value>(&mut channel, d).await {
+                    Some(Received::Message(msg)) => debug!("Got message '{}'", msg),
+                    Some(Received::Configured) => break,
+                    None => {}
+                }
             }
         }
     }
@@ -0,0 +1,188 @@
+use std::{fmt::Display, path::Path};
+
+use anyhow::bail;
+use renderer::message::{MessageError, NeoClockMessage};
+use serde::Deserialize;
+
+/// Where a message comes from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// A MQTT topic
+    Mqtt(String),
+    /// The HTTP API, including the WebSocket endpoint
+    Http,
+    /// The Unix domain socket
+    Socket,
+    Stdin,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Mqtt(topic) => write!(f, "MQTT topic '{}'", topic),
+            Source::Http => write!(f, "the HTTP API"),
+            Source::Socket => write!(f, "the socket"),
+            Source::Stdin => write!(f, "stdin"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SourceKind {
+    Mqtt,
+    Http,
+    Socket,
+    Stdin,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    source: SourceKind,
+    /// MQTT topic or topic filter with `+` and `#`, the rule matches all topics if not set
+    topic: Option<String>,
+    /// Allowed message types, `*` allows all of them
+    allow: Vec<String>,
+}
+
+impl Rule {
+    fn matches(&self, source: &Source) -> bool {
+        match (self.source, source) {
+            (SourceKind::Mqtt, Source::Mqtt(topic)) => match &self.topic {
+                Some(filter) => topic_matches(filter, topic),
+                None => true,
+            },
+            (SourceKind::Http, Source::Http) | (SourceKind::Socket, Source::Socket) | (SourceKind::Stdin, Source::Stdin) => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn allows(&self, message_type: &str) -> bool {
+        self.allow.iter().any(|t| t == "*" || t == message_type)
+    }
+}
+
+/// MQTT topic filter matching, `+` matches one level and `#` matches the rest
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for f in filter.split('/') {
+        match (f, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (f, Some(level)) if f == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Message types each source may use, the first rule matching the source decides, sources that
+/// match no rule can use all messages
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let acl: Acl = serde_json::from_reader(std::fs::File::open(path)?)?;
+        for rule in acl.rules.iter() {
+            if rule.topic.is_some() && rule.source != SourceKind::Mqtt {
+                bail!("Only MQTT rules can have a topic.");
+            }
+            for t in rule.allow.iter() {
+                if t != "*" && !NeoClockMessage::TYPES.contains(&t.as_str()) {
+                    bail!("Unknown message type '{}' in ACL.", t);
+                }
+            }
+        }
+        Ok(acl)
+    }
+
+    /// MQTT topics in the rules that should be subscribed, topic filters with wildcards are only
+    /// used for matching
+    pub fn topics(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .filter_map(|r| r.topic.as_deref())
+            .filter(|t| !t.contains(['+', '#']))
+    }
+
+    /// A `Batch` is allowed if all the messages in it are allowed
+    pub fn check(&self, source: &Source, msg: &NeoClockMessage) -> Result<(), MessageError> {
+        let rule = match self.rules.iter().find(|r| r.matches(source)) {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+        match msg {
+            NeoClockMessage::Batch { messages } => messages.iter().try_for_each(|m| self.check(source, m)),
+            msg if rule.allows(msg.message_type()) => Ok(()),
+            msg => Err(MessageError::Forbidden {
+                message_type: msg.message_type().to_string(),
+                origin: source.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use renderer::message::WidgetId;
+
+    use super::*;
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("#", "a"));
+    }
+
+    #[test]
+    fn test_check() {
+        let acl: Acl = serde_json::from_str(
+            r#"{"rules": [
+                {"source": "mqtt", "topic": "neoclock/notifications", "allow": ["Flyer"]},
+                {"source": "mqtt", "topic": "neoclock/widget/#", "allow": ["Show", "Hide"]},
+                {"source": "mqtt", "allow": ["*"]},
+                {"source": "http", "allow": ["Flyer"]},
+                {"source": "stdin", "allow": []}
+            ]}"#,
+        )
+        .unwrap();
+        let flyer = NeoClockMessage::Flyer {
+            id: WidgetId::Index(7),
+            msg: Default::default(),
+        };
+        let hide = NeoClockMessage::Hide { id: WidgetId::Index(7) };
+        let notifications = Source::Mqtt("neoclock/notifications".to_string());
+        assert!(acl.check(&notifications, &flyer).is_ok());
+        assert_eq!(
+            acl.check(&notifications, &hide),
+            Err(MessageError::Forbidden {
+                message_type: "Hide".to_string(),
+                origin: "MQTT topic 'neoclock/notifications'".to_string()
+            })
+        );
+        // A batch is allowed if all its messages are
+        let batch = |messages| NeoClockMessage::Batch { messages };
+        assert!(acl.check(&notifications, &batch(vec![flyer.clone()])).is_ok());
+        assert!(acl.check(&notifications, &batch(vec![flyer.clone(), hide.clone()])).is_err());
+        assert!(acl.check(&Source::Http, &batch(vec![batch(vec![hide.clone()])])).is_err());
+
+        assert!(acl.check(&Source::Mqtt("neoclock/widget/7/visible".to_string()), &hide).is_ok());
+        assert!(acl.check(&Source::Mqtt("neoclock".to_string()), &hide).is_ok());
+        assert!(acl.check(&Source::Http, &hide).is_err());
+        assert!(acl.check(&Source::Stdin, &flyer).is_err());
+        assert!(acl.check(&Source::Socket, &hide).is_ok());
+        assert_eq!(acl.topics().collect::<Vec<_>>(), vec!["neoclock/notifications"]);
+    }
+}
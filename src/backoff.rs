@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Delay before the first retry
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound of the retry delay
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Exponential backoff with equal jitter, delays are drawn between half of the ceiling and the
+/// ceiling, so a fleet of clocks doesn't hammer a restarted broker in lockstep
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { attempt: 0 }
+    }
+
+    /// Call after a successful connection
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Upper bound of the next delay, doubles on every failure until `MAX_DELAY`
+    fn ceiling(&self) -> Duration {
+        INITIAL_DELAY
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(MAX_DELAY)
+    }
+
+    /// The delay before the next retry
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling();
+        self.attempt = self.attempt.saturating_add(1);
+        // Never retry immediately, at least half of the ceiling
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new();
+        for ceiling in [1, 2, 4, 8, 16, 32, 60, 60] {
+            let delay = backoff.next_delay();
+            assert!(delay <= Duration::from_secs(ceiling));
+            assert!(delay >= Duration::from_secs(ceiling) / 2);
+        }
+        // The ceiling stays capped, even after many failures
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay <= MAX_DELAY && delay >= MAX_DELAY / 2);
+        }
+        assert_eq!(backoff.ceiling(), MAX_DELAY);
+        backoff.reset();
+        assert_eq!(backoff.ceiling(), INITIAL_DELAY);
+        let delay = backoff.next_delay();
+        assert!(delay <= INITIAL_DELAY && delay >= INITIAL_DELAY / 2);
+    }
+}
@@ -0,0 +1,272 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{anyhow, bail};
+use hyper::{header::CONTENT_TYPE, Body};
+use renderer::{
+    message::{ConfigureMessage, FlyerMessage, GifMessage, MoveMessage, NeoClockMessage, Request, Response, WidgetId},
+    DEFAULT_FLYER_ID, DEFAULT_FLYER_TTL,
+};
+use rumqttc::QoS;
+use structopt::StructOpt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::{config::Config, mqtt::Event, signing};
+
+/// How long to wait for the reply of the clock
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn parse_switch(s: &str) -> anyhow::Result<bool> {
+    match s.to_lowercase().as_str() {
+        "on" | "true" | "1" => Ok(true),
+        "off" | "false" | "0" => Ok(false),
+        _ => bail!("'{}' is not 'on' or 'off'.", s),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Send a message to a running clock, the messages can also be used without `send`
+    Send(Message),
+    /// Print the JSON Schema of config files or messages
+    Schema {
+        #[structopt(possible_values = &["config", "message"], default_value = "config")]
+        kind: String,
+    },
+    #[structopt(flatten)]
+    Message(Message),
+}
+
+fn print_schema(kind: &str) -> anyhow::Result<()> {
+    let schema = match kind {
+        "message" => renderer::message_schema(),
+        _ => crate::config_file::schema(),
+    };
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+/// Messages sent by the client subcommands, over the socket if `--socket` is set, otherwise the
+/// HTTP API if `--http` is set, otherwise MQTT
+#[derive(Debug, StructOpt)]
+pub enum Message {
+    /// Show a message on a Flyer widget
+    Flyer {
+        text: String,
+        #[structopt(long, help = "Seconds the message stays on the screen, same as plain text messages by default")]
+        ttl: Option<u32>,
+        #[structopt(long, help = "Widget name or index, the Flyer widget of the default layout by default")]
+        id: Option<WidgetId>,
+    },
+    /// Show a widget
+    Show { id: WidgetId },
+    /// Hide a widget
+    Hide { id: WidgetId },
+    /// Move a widget to a new position
+    Move { id: WidgetId, x: u32, y: u32 },
+    /// Switch a Gif widget to another file or URL, local paths are made absolute
+    Gif { id: WidgetId, url: String },
+    /// Turn the display on or off
+    Power {
+        #[structopt(parse(try_from_str = parse_switch))]
+        on: bool,
+    },
+    /// Set the global brightness
+    Brightness { brightness: u8 },
+    /// Change the config of a widget with a JSON merge patch, e.g. '{"text_color": "red"}'
+    Configure { id: WidgetId, patch: String },
+    /// Send a raw JSON message
+    Raw { json: String },
+}
+
+impl Message {
+    fn to_message(&self) -> anyhow::Result<NeoClockMessage> {
+        Ok(match self {
+            Message::Flyer { text, ttl, id } => NeoClockMessage::Flyer {
+                id: id.clone().unwrap_or(WidgetId::Index(DEFAULT_FLYER_ID)),
+                msg: FlyerMessage::new(text, ttl.unwrap_or(DEFAULT_FLYER_TTL)),
+            },
+            Message::Show { id } => NeoClockMessage::Show { id: id.clone() },
+            Message::Hide { id } => NeoClockMessage::Hide { id: id.clone() },
+            Message::Move { id, x, y } => NeoClockMessage::Move(MoveMessage {
+                id: id.clone(),
+                x: *x,
+                y: *y,
+            }),
+            Message::Gif { id, url } => {
+                // The clock may not run in the same directory
+                let url = match Path::new(url).canonicalize() {
+                    Ok(path) if !url.contains("://") => path.to_string_lossy().to_string(),
+                    _ => url.to_owned(),
+                };
+                NeoClockMessage::Gif {
+                    id: id.clone(),
+                    msg: GifMessage { url },
+                }
+            }
+            Message::Power { on } => NeoClockMessage::Power { on: *on },
+            Message::Brightness { brightness } => NeoClockMessage::Brightness {
+                brightness: *brightness,
+            },
+            Message::Configure { id, patch } => NeoClockMessage::Configure {
+                id: id.clone(),
+                msg: ConfigureMessage {
+                    patch: serde_json::from_str(patch)?,
+                },
+            },
+            Message::Raw { json } => serde_json::from_str(json)?,
+        })
+    }
+}
+
+/// The request as sent on the wire, signed if a secret is configured
+fn encode(opt: &Config, request: &Request) -> anyhow::Result<Vec<u8>> {
+    let payload = serde_json::to_vec(request)?;
+    Ok(match opt.get_secret() {
+        Some(secret) => signing::sign(secret.as_bytes(), &payload),
+        None => payload,
+    })
+}
+
+async fn send_socket(path: &Path, payload: Vec<u8>) -> anyhow::Result<Response> {
+    let mut stream = tokio::net::UnixStream::connect(path).await?;
+    let mut line = payload;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).await?;
+    Ok(serde_json::from_str(&reply)?)
+}
+
+async fn send_http(addr: &std::net::SocketAddr, payload: Vec<u8>) -> anyhow::Result<Response> {
+    let req = hyper::Request::post(format!("http://{}/messages", addr))
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(payload))?;
+    let res = hyper::Client::new().request(req).await?;
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn send_mqtt(opt: &Config, mut request: Request) -> anyhow::Result<Response> {
+    let (client, mut eventloop) = opt.get_cli_client()?;
+    let request_id = format!("{:016x}", rand::random::<u64>());
+    let reply_to = format!("{}/reply/{}", opt.topic, request_id);
+    request.request_id = Some(request_id);
+    request.reply_to = Some(reply_to.clone());
+    let payload = encode(opt, &request)?;
+    loop {
+        match eventloop.poll().await? {
+            Event::Connected { .. } => {
+                // Requests are processed in order, the reply can't arrive before the subscription
+                client.subscribe_many(vec![reply_to.clone()], QoS::AtLeastOnce).await?;
+                client.publish(&opt.topic, QoS::AtLeastOnce, false, payload.clone()).await?;
+            }
+            Event::Message { topic, payload } if topic == reply_to => {
+                return Ok(serde_json::from_slice(&payload)?);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub async fn run(opt: &Config, cmd: &Command) -> anyhow::Result<()> {
+    let msg = match cmd {
+        Command::Send(msg) | Command::Message(msg) => msg,
+        Command::Schema { kind } => return print_schema(kind),
+    };
+    let request = Request {
+        request_id: None,
+        reply_to: None,
+        message: msg.to_message()?,
+    };
+    let response = tokio::time::timeout(REPLY_TIMEOUT, async {
+        if let Some(path) = &opt.socket {
+            send_socket(path, encode(opt, &request)?).await
+        } else if let Some(addr) = &opt.http {
+            send_http(addr, encode(opt, &request)?).await
+        } else if opt.get_host().is_some() {
+            send_mqtt(opt, request).await
+        } else {
+            bail!("No transport is configured, use '--socket', '--http' or '--host'.")
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("No reply from the clock."))??;
+    println!("{}", serde_json::to_string(&response)?);
+    match response.error {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(args: &[&str]) -> NeoClockMessage {
+        let opt = Config::from_iter_safe(args).unwrap();
+        match opt.cmd.unwrap() {
+            Command::Send(msg) | Command::Message(msg) => msg.to_message().unwrap(),
+            Command::Schema { .. } => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        // The example of the README
+        match message(&["neoclock", "send", "flyer", "Build failed", "--ttl", "30"]) {
+            NeoClockMessage::Flyer { id, msg } => {
+                assert_eq!(id, WidgetId::Index(DEFAULT_FLYER_ID));
+                assert_eq!(msg.text, "Build failed");
+                assert_eq!(msg.ttl, 30);
+            }
+            _ => panic!(),
+        }
+        match message(&["neoclock", "flyer", "Hi", "--id", "alerts"]) {
+            NeoClockMessage::Flyer { id, msg } => {
+                assert_eq!(id, WidgetId::from("alerts"));
+                assert_eq!(msg.ttl, DEFAULT_FLYER_TTL);
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(
+            message(&["neoclock", "show", "3"]),
+            NeoClockMessage::Show { id: WidgetId::Index(3) }
+        ));
+        match message(&["neoclock", "move", "clock", "10", "20"]) {
+            NeoClockMessage::Move(m) => {
+                assert_eq!(m.id, WidgetId::from("clock"));
+                assert_eq!((m.x, m.y), (10, 20));
+            }
+            _ => panic!(),
+        }
+        match message(&["neoclock", "gif", "1", "https://example.com/cat.gif"]) {
+            NeoClockMessage::Gif { msg, .. } => assert_eq!(msg.url, "https://example.com/cat.gif"),
+            _ => panic!(),
+        }
+        assert!(matches!(
+            message(&["neoclock", "power", "off"]),
+            NeoClockMessage::Power { on: false }
+        ));
+        match message(&["neoclock", "configure", "clock", r#"{"text_color": "red"}"#]) {
+            NeoClockMessage::Configure { id, msg } => {
+                assert_eq!(id, WidgetId::from("clock"));
+                assert_eq!(msg.patch, serde_json::json!({"text_color": "red"}));
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(
+            message(&["neoclock", "raw", r#"{"type":"Hide","id":2}"#]),
+            NeoClockMessage::Hide { id: WidgetId::Index(2) }
+        ));
+        assert!(Config::from_iter_safe(["neoclock", "power", "maybe"]).is_err());
+    }
+
+    #[test]
+    fn test_schema_command() {
+        let opt = Config::from_iter_safe(["neoclock", "schema", "message"]).unwrap();
+        assert!(matches!(opt.cmd, Some(Command::Schema { kind }) if kind == "message"));
+        let opt = Config::from_iter_safe(["neoclock", "schema"]).unwrap();
+        assert!(matches!(opt.cmd, Some(Command::Schema { kind }) if kind == "config"));
+        assert!(Config::from_iter_safe(["neoclock", "schema", "status"]).is_err());
+    }
+}
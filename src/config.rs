@@ -1,48 +1,257 @@
-use std::time::Duration;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use rumqttc::{MqttOptions, AsyncClient, QoS, EventLoop};
-use structopt::StructOpt;
+use anyhow::bail;
+use log::info;
+use renderer::{message::NeoClockMessage, Compositing, Effect, WidgetConf};
+use rumqttc::{v5, LastWill, MqttOptions, QoS, TlsConfiguration, Transport};
+use structopt::{clap::ArgMatches, StructOpt};
+
+use crate::{
+    acl::Acl,
+    cli::Command,
+    config_file::Document,
+    mqtt::{Broker, Client, EventLoop},
+    signing::Verifier,
+};
+
+fn parse_qos(s: &str) -> anyhow::Result<QoS> {
+    match s {
+        "0" => Ok(QoS::AtMostOnce),
+        "1" => Ok(QoS::AtLeastOnce),
+        "2" => Ok(QoS::ExactlyOnce),
+        _ => bail!("QoS must be 0, 1 or 2."),
+    }
+}
+
+/// Whether an environment variable is set and not empty
+fn env_set(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|v| !v.is_empty())
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "neoclock", about = "LED Matrix Clock.")]
 pub struct Config {
-    #[structopt(short, long)]
+    #[structopt(short, long, help = "Config file, the options and environment variables override its values")]
     pub config: Option<String>,
 
     #[structopt(short = "r", long = "refresh-rate", default_value = "60")]
     pub fps: u64,
 
-    #[structopt(short, long, help = "MQTT Broker Host Name")]
+    #[structopt(long, default_value = "srgb", help = "Compositing mode, 'srgb' or 'linear'")]
+    pub compositing: Compositing,
+
+    #[structopt(
+        short,
+        long,
+        help = "MQTT Broker, 'host', 'host:port', 'mqtt://host:port' or 'mqtts://host:port'"
+    )]
     host: Option<String>,
 
     #[structopt(short, long, help = "Device Id")]
     device_id: Option<String>,
 
+    #[structopt(long, help = "MQTT Client Id, defaults to the device id")]
+    client_id: Option<String>,
+
+    #[structopt(long, help = "MQTT User Name, defaults to the device id")]
+    username: Option<String>,
+
     #[structopt(short, long, help = "Password")]
     password: Option<String>,
 
     #[structopt(short, long, help = "Use TLS")]
     use_tls: bool,
 
+    #[structopt(long, conflicts_with = "use_tls", help = "Don't use TLS, even if the config file does")]
+    no_tls: bool,
+
+    #[structopt(long, help = "CA certificates file in PEM format, the system CAs are used if not set")]
+    ca_file: Option<String>,
+
+    #[structopt(long, help = "Client certificate file in PEM format")]
+    client_cert: Option<String>,
+
+    #[structopt(long, help = "Client private key file in PEM format")]
+    client_key: Option<String>,
+
+    #[structopt(long, use_delimiter = true, help = "ALPN protocols, comma separated")]
+    alpn: Vec<String>,
+
+    #[structopt(long, default_value = "5", help = "MQTT keep-alive in seconds, 0 to disable")]
+    keep_alive: u64,
+
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_qos), help = "QoS of the subscriptions, 0, 1 or 2")]
+    pub qos: QoS,
+
+    #[structopt(long, help = "Use MQTT v5 instead of v3.1.1")]
+    mqtt_v5: bool,
+
+    #[structopt(long, conflicts_with = "mqtt_v5", help = "Use MQTT v3.1.1, even if the config file uses v5")]
+    no_mqtt_v5: bool,
+
     #[structopt(short, long, default_value = "neoclock", help = "MQTT Topic")]
-    topic: String,
+    pub topic: String,
+
+    #[structopt(long, help = "Serve the HTTP API on this address, e.g. '127.0.0.1:8080'")]
+    pub http: Option<SocketAddr>,
+
+    #[structopt(long, help = "Accept JSON lines on this Unix domain socket")]
+    pub socket: Option<PathBuf>,
+
+    #[structopt(long, help = "Accept JSON lines on stdin, replies are written to stdout")]
+    pub stdin: bool,
+
+    #[structopt(long, help = "Shared secret to sign messages with, unsigned messages are rejected if set")]
+    secret: Option<String>,
+
+    #[structopt(long, default_value = "30", help = "Seconds a signed message stays valid")]
+    max_age: u64,
+
+    #[structopt(long, help = "JSON file of the message types each source may use")]
+    pub acl: Option<PathBuf>,
+
+    #[structopt(long, help = "File the runtime state is saved to, and restored from at startup")]
+    pub state_file: Option<PathBuf>,
+
+    #[structopt(long, help = "Publish Home Assistant MQTT discovery")]
+    pub homeassistant: bool,
+
+    #[structopt(long, default_value = "homeassistant", help = "Home Assistant discovery prefix")]
+    pub ha_prefix: String,
+
+    #[structopt(long, help = "Directory of GIF files offered by the Home Assistant select entity")]
+    pub ha_gif_dir: Option<String>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+
+    /// The widgets of the config file, `None` if there is no config file
+    #[structopt(skip)]
+    pub widgets: Option<Vec<WidgetConf>>,
+
+    #[structopt(skip)]
+    pub scenes: BTreeMap<String, Vec<NeoClockMessage>>,
+
+    #[structopt(skip)]
+    pub effects: Vec<Effect>,
+
+    #[structopt(skip)]
+    pub brightness: Option<u8>,
 }
 
 impl Config {
-    fn get_host(&self) -> String {
+    /// Parse the command line and merge the config file
+    pub fn load() -> anyhow::Result<Self> {
+        Self::load_from(std::env::args_os())
+    }
+
+    fn load_from<I>(args: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator,
+        I::Item: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = Self::clap().get_matches_from(args);
+        let mut opt = Self::from_clap(&matches);
+        if let Some(path) = &opt.config {
+            info!("Using config file at '{}'.", path);
+            let doc = Document::load(Path::new(path))?;
+            opt.merge(doc, &matches)?;
+        }
+        Ok(opt)
+    }
+
+    /// Values of the document are only used if they are not set on the command line, or by the
+    /// environment variables
+    fn merge(&mut self, doc: Document, matches: &ArgMatches) -> anyhow::Result<()> {
+        let default = |name: &str| matches.occurrences_of(name) == 0;
+        if let (true, Some(fps)) = (default("fps"), doc.display.fps) {
+            self.fps = fps;
+        }
+        if let (true, Some(compositing)) = (default("compositing"), doc.render.compositing) {
+            self.compositing = compositing;
+        }
+        self.brightness = doc.display.brightness;
+        self.effects = doc.render.effects;
+        self.widgets = Some(doc.widgets);
+        self.scenes = doc.scenes;
+
+        let mqtt = doc.mqtt;
+        for (value, env, file) in [
+            (&mut self.host, "NEOCLOCK_HOSTNAME", mqtt.host),
+            (&mut self.device_id, "NEOCLOCK_DEVICE_ID", mqtt.device_id),
+            (&mut self.username, "NEOCLOCK_USERNAME", mqtt.username),
+            (&mut self.password, "NEOCLOCK_PASSWORD", mqtt.password),
+        ] {
+            if value.is_none() && !env_set(env) {
+                *value = file;
+            }
+        }
+        for (value, file) in [
+            (&mut self.client_id, mqtt.client_id),
+            (&mut self.ca_file, mqtt.ca_file),
+            (&mut self.client_cert, mqtt.client_cert),
+            (&mut self.client_key, mqtt.client_key),
+        ] {
+            if value.is_none() {
+                *value = file;
+            }
+        }
+        if self.alpn.is_empty() {
+            self.alpn = mqtt.alpn;
+        }
+        if !self.use_tls && !self.no_tls {
+            self.use_tls = mqtt.tls;
+        }
+        if !self.mqtt_v5 && !self.no_mqtt_v5 {
+            self.mqtt_v5 = mqtt.v5;
+        }
+        if let (true, Some(keep_alive)) = (default("keep-alive"), mqtt.keep_alive) {
+            self.keep_alive = keep_alive;
+        }
+        if let (true, Some(qos)) = (default("qos"), mqtt.qos) {
+            self.qos = parse_qos(&qos.to_string())?;
+        }
+        if let (true, Some(topic)) = (default("topic"), mqtt.topic) {
+            self.topic = topic;
+        }
+        Ok(())
+    }
+
+    /// The MQTT broker, MQTT is disabled if it's not set
+    pub fn get_host(&self) -> Option<String> {
         match &self.host {
-            Some(s) => s.to_owned(),
-            None => std::env::var("NEOCLOCK_HOSTNAME").unwrap_or_else(|_| "localhost".to_string()),
+            Some(s) => Some(s.to_owned()),
+            None => std::env::var("NEOCLOCK_HOSTNAME").ok(),
         }
+        .filter(|s| !s.is_empty())
     }
 
-    fn get_device_id(&self) -> String {
+    pub fn get_device_id(&self) -> String {
         match &self.device_id {
             Some(s) => s.to_owned(),
             None => std::env::var("NEOCLOCK_DEVICE_ID").unwrap_or_else(|_| "neoclock".to_string()),
         }
     }
 
+    fn get_client_id(&self) -> String {
+        match &self.client_id {
+            Some(s) => s.to_owned(),
+            None => self.get_device_id(),
+        }
+    }
+
+    fn get_username(&self) -> Option<String> {
+        match &self.username {
+            Some(s) => Some(s.to_owned()),
+            None => std::env::var("NEOCLOCK_USERNAME").ok(),
+        }
+    }
+
     fn get_password(&self) -> String {
         match &self.password {
             Some(s) => s.to_owned(),
@@ -50,25 +259,173 @@ impl Config {
         }
     }
 
-    pub async fn get_receiver(&self) -> anyhow::Result<EventLoop> {
-        let host = self.get_host();
-        let mut mqttoptions = if host.contains(':') {
-            let parts: Vec<&str> = host.split(':').collect();
-            let host = parts[0];
-            let port = parts[1].parse::<u16>()?;
-            MqttOptions::new(self.get_device_id(), host, port)
+    /// The shared secret of signed messages, signing is disabled if it's not set
+    pub fn get_secret(&self) -> Option<String> {
+        match &self.secret {
+            Some(s) => Some(s.to_owned()),
+            None => std::env::var("NEOCLOCK_SECRET").ok(),
+        }
+        .filter(|s| !s.is_empty())
+    }
+
+    pub fn get_verifier(&self) -> Option<Verifier> {
+        self.get_secret()
+            .map(|secret| Verifier::new(secret.as_bytes(), Duration::from_secs(self.max_age)))
+    }
+
+    pub fn get_acl(&self) -> anyhow::Result<Option<Acl>> {
+        match &self.acl {
+            Some(path) => {
+                info!("Using ACL at '{}'.", path.display());
+                Ok(Some(Acl::load(path)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// User name and password, `None` for anonymous access
+    fn get_credentials(&self) -> Option<(String, String)> {
+        let password = self.get_password();
+        match self.get_username() {
+            Some(username) => Some((username, password)),
+            None if !password.is_empty() => Some((self.get_device_id(), password)),
+            None => None,
+        }
+    }
+
+    fn get_tls_config(&self) -> anyhow::Result<TlsConfiguration> {
+        let client_auth = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((std::fs::read(cert)?, std::fs::read(key)?)),
+            (None, None) => None,
+            _ => bail!("'--client-cert' and '--client-key' must be used together."),
+        };
+        let alpn = if self.alpn.is_empty() {
+            None
         } else {
-            MqttOptions::new(self.get_device_id(), self.get_host(), if self.use_tls { 8883 } else { 1883 })
-        };        mqttoptions.set_keep_alive(Duration::from_secs(5));
-        if self.use_tls {
-            mqttoptions.set_transport(rumqttc::Transport::tls_with_config(rumqttc::TlsConfiguration::default()));
+            Some(self.alpn.iter().map(|p| p.as_bytes().to_vec()).collect())
+        };
+        match &self.ca_file {
+            Some(ca) => Ok(TlsConfiguration::Simple {
+                ca: std::fs::read(ca)?,
+                alpn,
+                client_auth,
+            }),
+            None if client_auth.is_none() && alpn.is_none() => Ok(TlsConfiguration::default()),
+            None => bail!("'--ca-file' is required to use client certificates or ALPN."),
         }
+    }
+
+    /// Topic of the retained device status document
+    pub fn get_status_topic(&self) -> String {
+        format!("{}/status", self.topic)
+    }
+
+    /// Topic of the retained `online`/`offline` availability, `offline` is the Last Will
+    pub fn get_availability_topic(&self) -> String {
+        format!("{}/availability", self.topic)
+    }
+
+    /// Subscriptions are not made here as they need to be renewed on every connection, see
+    /// `transport::mqtt`
+    pub async fn get_receiver(&self) -> anyhow::Result<(Client, EventLoop)> {
+        self.connect(self.get_client_id(), true)
+    }
+
+    /// Connection of the `send` subcommands, with an unique client id so it doesn't kick the clock
+    /// off the broker, and without the Last Will
+    pub fn get_cli_client(&self) -> anyhow::Result<(Client, EventLoop)> {
+        let client_id = format!("{}-cli-{:08x}", self.get_client_id(), rand::random::<u32>());
+        self.connect(client_id, false)
+    }
 
-        if !self.get_password().is_empty() {
-            mqttoptions.set_credentials(self.get_device_id(), self.get_password());
+    fn connect(&self, client_id: String, last_will: bool) -> anyhow::Result<(Client, EventLoop)> {
+        let host = match self.get_host() {
+            Some(host) => host,
+            None => bail!("MQTT broker is not configured."),
+        };
+        let broker = Broker::parse(&host, self.use_tls)?;
+        let transport = if broker.tls {
+            Transport::tls_with_config(self.get_tls_config()?)
+        } else {
+            Transport::tcp()
+        };
+        let keep_alive = Duration::from_secs(self.keep_alive);
+        if self.mqtt_v5 {
+            let mut mqttoptions = v5::MqttOptions::new(client_id, broker.host, broker.port);
+            mqttoptions.set_keep_alive(keep_alive).set_transport(transport);
+            if last_will {
+                mqttoptions.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                    self.get_availability_topic(),
+                    "offline",
+                    v5::mqttbytes::QoS::AtLeastOnce,
+                    true,
+                    None,
+                ));
+            }
+            if let Some((username, password)) = self.get_credentials() {
+                mqttoptions.set_credentials(username, password);
+            }
+            let (client, eventloop) = v5::AsyncClient::new(mqttoptions, 10);
+            Ok((Client::V5(client), EventLoop::V5(eventloop)))
+        } else {
+            let mut mqttoptions = MqttOptions::new(client_id, broker.host, broker.port);
+            mqttoptions.set_keep_alive(keep_alive).set_transport(transport);
+            if last_will {
+                mqttoptions.set_last_will(LastWill::new(
+                    self.get_availability_topic(),
+                    "offline",
+                    QoS::AtLeastOnce,
+                    true,
+                ));
+            }
+            if let Some((username, password)) = self.get_credentials() {
+                mqttoptions.set_credentials(username, password);
+            }
+            let (client, eventloop) = rumqttc::AsyncClient::new(mqttoptions, 10);
+            Ok((Client::V4(client), EventLoop::V4(eventloop)))
         }
-        let (client, eventloop) = AsyncClient::new(mqttoptions, 10);
-        client.subscribe(self.topic.clone(), QoS::AtMostOnce).await?;
-        Ok(eventloop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let path = std::env::temp_dir().join(format!("neoclock-config-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"display": {"fps": 30, "brightness": 100}, "mqtt": {"client_id": "file", "qos": 2, "topic": "file", "keep_alive": 20, "tls": true, "v5": true}}"#,
+        )
+        .unwrap();
+        let config = path.to_string_lossy().to_string();
+
+        let opt = Config::load_from(["neoclock", "--config", &config, "--topic", "cli"]).unwrap();
+        assert_eq!(opt.fps, 30);
+        assert_eq!(opt.brightness, Some(100));
+        assert_eq!(opt.get_client_id(), "file");
+        assert_eq!(opt.qos, QoS::ExactlyOnce);
+        assert_eq!(opt.topic, "cli");
+        assert_eq!(opt.keep_alive, 20);
+        assert!(opt.use_tls && opt.mqtt_v5);
+        assert_eq!(opt.widgets.map(|w| w.len()), Some(0));
+
+        let opt =
+            Config::load_from(["neoclock", "--config", &config, "-r", "10", "--client-id", "cli", "--keep-alive", "0"])
+                .unwrap();
+        assert_eq!(opt.fps, 10);
+        assert_eq!(opt.keep_alive, 0);
+        assert_eq!(opt.get_client_id(), "cli");
+        assert_eq!(opt.topic, "file");
+
+        // Flags of the file can be turned off
+        let opt = Config::load_from(["neoclock", "--config", &config, "--no-tls", "--no-mqtt-v5"]).unwrap();
+        assert!(!opt.use_tls && !opt.mqtt_v5);
+        std::fs::remove_file(&path).unwrap();
+
+        let opt = Config::load_from(["neoclock"]).unwrap();
+        assert_eq!(opt.fps, 60);
+        assert!(opt.widgets.is_none());
     }
 }
@@ -0,0 +1,361 @@
+use std::{collections::BTreeMap, fmt::Display, path::Path};
+
+use anyhow::{anyhow, bail};
+use renderer::{message::NeoClockMessage, Compositing, Effect, WidgetConf};
+use schemars::{gen::SchemaSettings, schema::RootSchema, JsonSchema};
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplaySection {
+    /// Frames per second, `--refresh-rate`
+    pub fps: Option<u64>,
+    /// Global brightness at startup, 255 is the full brightness
+    pub brightness: Option<u8>,
+}
+
+/// Same as the MQTT command line options
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSection {
+    pub host: Option<String>,
+    pub device_id: Option<String>,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: bool,
+    pub ca_file: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub alpn: Vec<String>,
+    pub keep_alive: Option<u64>,
+    /// QoS of the subscriptions, 0, 1 or 2
+    pub qos: Option<u8>,
+    /// Use MQTT v5 instead of v3.1.1
+    pub v5: bool,
+    pub topic: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderSection {
+    pub compositing: Option<Compositing>,
+    /// Post-processing effect chain at startup
+    pub effects: Vec<Effect>,
+}
+
+/// The config document, command line options and environment variables override its values
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Document {
+    pub display: DisplaySection,
+    pub mqtt: MqttSection,
+    pub render: RenderSection,
+    pub widgets: Vec<WidgetConf>,
+    /// Named lists of messages applied by `Scene` messages, a scene can't contain scenes
+    pub scenes: BTreeMap<String, Vec<NeoClockMessage>>,
+}
+
+/// Config files are either a document, or only the widgets in the old format, only used for
+/// the schema, see `Document::parse`
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code, clippy::large_enum_variant)]
+enum ConfigFile {
+    Widgets(Vec<WidgetConf>),
+    Document(Document),
+}
+
+/// Config file formats, from the extension of the file, JSON by default
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// Start of the JSON object that ends at `end`, the position of the errors of buffered values
+fn json_object_start(text: &str, end: usize) -> Option<usize> {
+    let (mut open, mut last) = (vec![], None);
+    let (mut in_string, mut escape) = (false, false);
+    for (i, c) in text.char_indices().take_while(|(i, _)| *i < end) {
+        match c {
+            _ if escape => escape = false,
+            '\\' if in_string => escape = true,
+            '"' => in_string = !in_string,
+            '{' if !in_string => open.push(i),
+            '}' if !in_string => last = open.pop(),
+            _ => {}
+        }
+    }
+    last
+}
+
+/// Whether the string at `pos` is the value of a color field, `color: x`, `"color": "x"` or
+/// `color = "x"`
+fn is_color_value(text: &str, pos: usize, len: usize) -> bool {
+    let line_start = text[..pos].rfind('\n').map(|i| i + 1).unwrap_or_default();
+    let key = text[line_start..pos].trim_end_matches(['"', '\'']).trim_end();
+    let key = match key.strip_suffix([':', '=']) {
+        Some(key) => key.trim_end().trim_end_matches(['"', '\'']),
+        None => return false,
+    };
+    let next = text[pos + len..].chars().next();
+    key.ends_with("color") && next.is_none_or(|c| matches!(c, '"' | '\'' | ',' | '}' | ']' | '#') || c.is_whitespace())
+}
+
+/// Colors are parsed from buffered values of the `type` tagged widgets, the parsers only report
+/// the position of the widget, `start`, the line of an invalid color is the one of the first
+/// color field of the widget with this value
+fn located<E: Display>(text: &str, start: Option<usize>, e: E) -> anyhow::Error {
+    let e = e.to_string();
+    let color = renderer::invalid_color(&e).filter(|c| !c.is_empty());
+    let line = color.zip(start).and_then(|(color, start)| {
+        let (pos, _) = text
+            .get(start..)?
+            .match_indices(color)
+            .find(|(i, _)| is_color_value(text, start + i, color.len()))?;
+        Some(text[..start + pos].matches('\n').count() + 1)
+    });
+    match (color, line) {
+        (Some(color), Some(line)) => anyhow!("line {}: invalid color '{}'", line, color),
+        _ => anyhow!(e),
+    }
+}
+
+fn json_error(text: &str, e: serde_json::Error) -> anyhow::Error {
+    let line_start: usize = text.split_inclusive('\n').take(e.line().saturating_sub(1)).map(str::len).sum();
+    let start = json_object_start(text, line_start + e.column());
+    located(text, start, e)
+}
+
+fn toml_error(text: &str, e: toml::de::Error) -> anyhow::Error {
+    let start = e.span().map(|s| s.start);
+    located(text, start, e)
+}
+
+fn yaml_error(text: &str, e: serde_yaml::Error) -> anyhow::Error {
+    let start = e.location().map(|l| l.index());
+    located(text, start, e)
+}
+
+fn contains_scene(msg: &NeoClockMessage) -> bool {
+    match msg {
+        NeoClockMessage::Scene { .. } => true,
+        NeoClockMessage::Batch { messages } => messages.iter().any(contains_scene),
+        _ => false,
+    }
+}
+
+impl Document {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text, Format::of(path)).map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+
+    /// Documents are deserialized from the text, not from a value, to keep the positions in the
+    /// errors, TOML files can't be an array so they are always a document
+    fn parse(text: &str, format: Format) -> anyhow::Result<Self> {
+        let widgets = |widgets| Document {
+            widgets,
+            ..Default::default()
+        };
+        let doc = match format {
+            Format::Json => {
+                if serde_json::from_str::<serde_json::Value>(text)?.is_array() {
+                    serde_json::from_str(text).map(widgets).map_err(|e| json_error(text, e))?
+                } else {
+                    serde_json::from_str(text).map_err(|e| json_error(text, e))?
+                }
+            }
+            Format::Toml => toml::from_str(text).map_err(|e| toml_error(text, e))?,
+            Format::Yaml => {
+                if serde_yaml::from_str::<serde_yaml::Value>(text)?.is_sequence() {
+                    serde_yaml::from_str(text).map(widgets).map_err(|e| yaml_error(text, e))?
+                } else {
+                    serde_yaml::from_str(text).map_err(|e| yaml_error(text, e))?
+                }
+            }
+        };
+        renderer::check_names(&doc.widgets)?;
+        for (name, messages) in doc.scenes.iter() {
+            if messages.iter().any(contains_scene) {
+                bail!("Scene '{}' contains a Scene message.", name);
+            }
+        }
+        Ok(doc)
+    }
+}
+
+/// JSON Schema of config files
+pub fn schema() -> RootSchema {
+    let mut schema = SchemaSettings::draft07().into_generator().into_root_schema_for::<ConfigFile>();
+    schema.schema.metadata().title = Some("NeoClock config".to_string());
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonschema::JSONSchema;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn from_json(value: serde_json::Value) -> anyhow::Result<Document> {
+        Document::parse(&value.to_string(), Format::Json)
+    }
+
+    #[test]
+    fn test_document() {
+        let widgets = json!([{"type": "Solid", "name": "bg", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"}]);
+        let doc = from_json(widgets.clone()).unwrap();
+        assert_eq!(doc.widgets.len(), 1);
+        assert!(doc.scenes.is_empty());
+
+        let doc = from_json(json!({
+            "display": {"fps": 30},
+            "mqtt": {"host": "broker", "qos": 1},
+            "render": {"compositing": "linear", "effects": [{"type": "Invert"}]},
+            "widgets": widgets,
+            "scenes": {"night": [{"type": "Hide", "id": "bg"}]}
+        }))
+        .unwrap();
+        assert_eq!(doc.display.fps, Some(30));
+        assert_eq!(doc.mqtt.host.as_deref(), Some("broker"));
+        assert_eq!(doc.render.compositing, Some(Compositing::Linear));
+        assert_eq!(doc.widgets.len(), 1);
+        assert_eq!(doc.scenes["night"].len(), 1);
+
+        assert!(from_json(json!({"display": {"frames": 30}})).is_err());
+        assert!(from_json(json!({"scenes": {"a": [{"type": "Batch", "messages": [{"type": "Scene", "name": "b"}]}]}})).is_err());
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = JSONSchema::compile(&serde_json::to_value(schema()).unwrap()).unwrap();
+        let solid = json!({"type": "Solid", "name": "bg", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"});
+        let clock = json!({
+            "type": "Clock", "x": 1, "y": 2, "font_path": "font.bdf", "font_height": 8.0,
+            "text_color": "white", "background_color": "rgba(0,0,0,0)", "mask": {"part": "bg"}
+        });
+        let group = json!({"type": "Group", "x": 0, "y": 0, "children": [
+            {"type": "Solid", "x": 0, "y": 0, "width": 4, "height": 4, "color": "blue"}
+        ]});
+        let widgets = json!([
+            solid,
+            clock,
+            group,
+            {"type": "Calendar", "x": 0, "y": 0},
+            {"type": "MatrixRain", "x": 0, "y": 0, "color": "green", "speed": 2},
+            {"type": "Gif", "x": 0, "y": 0, "location": "robot.gif"},
+            {"type": "Flyer", "x": 0, "y": 0, "speed": 1},
+            {"type": "Wigwag", "x": 0, "y": 0, "text": "Hi"}
+        ]);
+        let config: Value = serde_json::from_str(include_str!("../config-test.json")).unwrap();
+        // The schema must accept exactly what the parser accepts
+        for (sample, valid) in [
+            (config, true),
+            (widgets.clone(), true),
+            (
+                json!({
+                    "display": {"fps": 30, "brightness": 128},
+                    "mqtt": {"host": "broker", "qos": 1, "v5": true, "alpn": ["mqtt"]},
+                    "render": {"compositing": "linear", "effects": [{"type": "Invert"}, {"type": "BoxBlur", "radius": 1}]},
+                    "widgets": widgets,
+                    "scenes": {"night": [{"type": "Hide", "id": "bg"}, {"type": "Brightness", "brightness": 16}]}
+                }),
+                true,
+            ),
+            (json!({}), true),
+            (json!([{"type": "Solid", "x": 0, "y": 0, "width": 8, "height": 8, "color": 1}]), false),
+            (json!([{"type": "Solid", "x": 0, "y": 0, "width": 8, "color": "red"}]), false),
+            (json!([{"type": "Clock", "x": 0, "y": 0, "font_height": "big"}]), false),
+            (json!([{"type": "Unknown", "x": 0, "y": 0}]), false),
+            (json!([{"type": "Calendar", "y": 0}]), false),
+            (json!({"display": {"frames": 30}}), false),
+            (json!({"mqtt": {"qos": "high"}}), false),
+            (json!({"widgets": [{"type": "Calendar", "y": 0}]}), false),
+            (json!({"scenes": {"night": [{"type": "Reboot"}]}}), false),
+        ] {
+            assert_eq!(schema.is_valid(&sample), valid, "schema, {}", sample);
+            assert_eq!(from_json(sample.clone()).is_ok(), valid, "parser, {}", sample);
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(Format::of(Path::new("a.toml")), Format::Toml);
+        assert_eq!(Format::of(Path::new("a.yml")), Format::Yaml);
+        assert_eq!(Format::of(Path::new("a")), Format::Json);
+
+        let widgets = |path: &str| format!("{:?}", Document::load(Path::new(path)).unwrap().widgets);
+        let json = widgets("config-test.json");
+        assert_eq!(widgets("config-test.toml"), json);
+        assert_eq!(widgets("config-test.yaml"), json);
+
+        let yaml = "- type: Solid\n  x: 0\n  y: 0\n  width: 8\n  height: 8\n  color: rgb(0,0,0)\n";
+        assert_eq!(Document::parse(yaml, Format::Yaml).unwrap().widgets.len(), 1);
+    }
+
+    #[test]
+    fn test_color_line() {
+        let err = |text: &str, format| Document::parse(text, format).unwrap_err().to_string();
+        let json = "{\"widgets\": [{\n  \"type\": \"Solid\", \"x\": 0, \"y\": 0, \"width\": 8, \"height\": 8,\n  \"color\": \"blurple\"\n}]}";
+        assert_eq!(err(json, Format::Json), "line 3: invalid color 'blurple'");
+        let toml = "[[widgets]]\ntype = \"Solid\"\nx = 0\ny = 0\nwidth = 8\nheight = 8\ncolor = \"blurple\"\n";
+        assert_eq!(err(toml, Format::Toml), "line 7: invalid color 'blurple'");
+        let yaml = "widgets:\n  # Background\n  - type: Clock\n    x: 0\n    y: 0\n    width: 8\n    height: 8\n    text_color: blurple\n";
+        assert_eq!(err(yaml, Format::Yaml), "line 8: invalid color 'blurple'");
+        // Other errors keep the position of the parser
+        assert!(err("[[widgets]]\ntype = \"Solid\"\n", Format::Toml).contains("line 1"));
+    }
+
+    #[test]
+    fn test_repeated_color() {
+        let err = |text: &str, format| Document::parse(text, format).unwrap_err().to_string();
+        let json = r#"[
+            {"type": "Solid", "name": "blurple", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"},
+            {"type": "Flyer", "x": 0, "y": 0, "text": "blurple", "text_color": "red",
+                "background_color": "blurple"},
+            {"type": "Solid", "x": 0, "y": 0, "width": 8, "height": 8, "color": "blurple"}
+        ]"#;
+        assert_eq!(err(json, Format::Json), "line 4: invalid color 'blurple'");
+        let toml = r#"
+            # Not blurple
+            [[widgets]]
+            type = "Solid"
+            name = "blurple"
+            x = 0
+            y = 0
+            width = 8
+            height = 8
+            color = "red"
+
+            [[widgets]]
+            type = "Solid"
+            x = 0
+            y = 0
+            width = 8
+            height = 8
+            color = "blurple"
+        "#;
+        assert_eq!(err(toml, Format::Toml), "line 18: invalid color 'blurple'");
+        let yaml = r#"
+            # Not blurple
+            - {type: Solid, name: blurple, x: 0, y: 0, width: 8, height: 8, color: red}
+            - {type: Solid, x: 0, y: 0, width: 8, height: 8, color: blurple}
+            - {type: Solid, x: 0, y: 0, width: 8, height: 8, color: blurple}
+        "#;
+        assert_eq!(err(yaml, Format::Yaml), "line 4: invalid color 'blurple'");
+    }
+}
@@ -0,0 +1,325 @@
+use std::path::PathBuf;
+
+use log::warn;
+use renderer::{
+    message::{FlyerMessage, GifMessage, NeoClockMessage, WidgetId},
+    WidgetStatus, DEFAULT_FLYER_TTL,
+};
+use serde_json::{json, Value};
+
+/// Home Assistant MQTT discovery, exposes the clock as a device with a light, a text, selects of the
+/// GIF and the scene and a switch per widget, and translates the entity commands into `NeoClockMessage`s
+pub struct HomeAssistant {
+    prefix: String,
+    device_id: String,
+    base: String,
+    status_topic: String,
+    availability_topic: String,
+    widgets: Vec<WidgetStatus>,
+    gif_dir: Option<PathBuf>,
+    gifs: Vec<String>,
+    scenes: Vec<String>,
+}
+
+impl HomeAssistant {
+    pub fn new(
+        prefix: &str,
+        device_id: &str,
+        topic: &str,
+        status_topic: &str,
+        availability_topic: &str,
+        widgets: Vec<WidgetStatus>,
+        gif_dir: Option<&str>,
+    ) -> Self {
+        let gif_dir = gif_dir.map(PathBuf::from);
+        let mut gifs: Vec<String> = match &gif_dir {
+            Some(dir) => match std::fs::read_dir(dir) {
+                Ok(entries) => entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .filter(|n| n.to_lowercase().ends_with(".gif"))
+                    .collect(),
+                Err(e) => {
+                    warn!("Failed to list GIF directory '{}', error is '{}'.", dir.display(), e);
+                    Default::default()
+                }
+            },
+            None => Default::default(),
+        };
+        gifs.sort();
+        Self {
+            prefix: prefix.to_owned(),
+            device_id: device_id.to_owned(),
+            base: format!("{}/ha", topic),
+            status_topic: status_topic.to_owned(),
+            availability_topic: availability_topic.to_owned(),
+            widgets,
+            gif_dir,
+            gifs,
+            scenes: Default::default(),
+        }
+    }
+
+    /// Names of the scenes offered by the scene select
+    pub fn with_scenes(mut self, scenes: Vec<String>) -> Self {
+        self.scenes = scenes;
+        self
+    }
+
+    /// Topic filter of all entity command topics
+    pub fn command_filter(&self) -> String {
+        format!("{}/#", self.base)
+    }
+
+    fn first_widget(&self, kind: &str) -> Option<WidgetId> {
+        self.widgets.iter().find(|w| w.kind == kind).map(|w| w.id.into())
+    }
+
+    fn config_topic(&self, component: &str, object_id: &str) -> String {
+        format!(
+            "{}/{}/{}/{}/config",
+            self.prefix, component, self.device_id, object_id
+        )
+    }
+
+    /// Retained discovery payloads, as `(topic, payload)` pairs
+    pub fn discovery(&self) -> Vec<(String, Value)> {
+        let device = json!({
+            "identifiers": [self.device_id],
+            "name": "NeoClock",
+            "model": "LED Matrix Clock",
+            "manufacturer": "NeoClock",
+        });
+        let availability = json!([{ "topic": self.availability_topic }]);
+        let mut ret = vec![(
+            self.config_topic("light", "display"),
+            json!({
+                "name": "Display",
+                "unique_id": format!("{}_display", self.device_id),
+                "device": device,
+                "availability": availability,
+                "command_topic": format!("{}/power/set", self.base),
+                "state_topic": self.status_topic,
+                "state_value_template": "{{ 'ON' if value_json.display.power else 'OFF' }}",
+                "brightness_command_topic": format!("{}/brightness/set", self.base),
+                "brightness_state_topic": self.status_topic,
+                "brightness_value_template": "{{ value_json.display.brightness }}",
+                "brightness_scale": 255,
+            }),
+        )];
+
+        if self.first_widget("Flyer").is_some() {
+            ret.push((
+                self.config_topic("text", "message"),
+                json!({
+                    "name": "Message",
+                    "unique_id": format!("{}_message", self.device_id),
+                    "device": device,
+                    "availability": availability,
+                    "command_topic": format!("{}/flyer/set", self.base),
+                    "max": 255,
+                }),
+            ));
+        }
+
+        if self.first_widget("Gif").is_some() && !self.gifs.is_empty() {
+            ret.push((
+                self.config_topic("select", "gif"),
+                json!({
+                    "name": "GIF",
+                    "unique_id": format!("{}_gif", self.device_id),
+                    "device": device,
+                    "availability": availability,
+                    "command_topic": format!("{}/gif/set", self.base),
+                    "options": self.gifs,
+                    "optimistic": true,
+                }),
+            ));
+        }
+
+        if !self.scenes.is_empty() {
+            ret.push((
+                self.config_topic("select", "scene"),
+                json!({
+                    "name": "Scene",
+                    "unique_id": format!("{}_scene", self.device_id),
+                    "device": device,
+                    "availability": availability,
+                    "command_topic": format!("{}/scene/set", self.base),
+                    "options": self.scenes,
+                    "optimistic": true,
+                }),
+            ));
+        }
+
+        for w in self.widgets.iter() {
+            ret.push((
+                self.config_topic("switch", &format!("widget_{}", w.id)),
+                json!({
+                    "name": match &w.name {
+                        Some(name) => name.to_owned(),
+                        None => format!("Widget {} ({})", w.id, w.kind),
+                    },
+                    "unique_id": format!("{}_widget_{}", self.device_id, w.id),
+                    "device": device,
+                    "availability": availability,
+                    "command_topic": format!("{}/widget/{}/set", self.base, w.id),
+                    "state_topic": self.status_topic,
+                    "value_template": format!(
+                        "{{{{ 'ON' if value_json.widgets[{}].visible else 'OFF' }}}}",
+                        w.id
+                    ),
+                }),
+            ));
+        }
+        ret
+    }
+
+    /// Translate a command published by Home Assistant, returns `None` if `topic` is not an entity
+    /// command topic or the payload is invalid
+    pub fn translate(&self, topic: &str, payload: &[u8]) -> Option<NeoClockMessage> {
+        let command = topic.strip_prefix(&self.base)?.strip_prefix('/')?;
+        let payload = std::str::from_utf8(payload).ok()?.trim();
+        let parts: Vec<&str> = command.split('/').collect();
+        match parts.as_slice() {
+            ["power", "set"] => Some(NeoClockMessage::Power {
+                on: parse_switch(payload)?,
+            }),
+            ["brightness", "set"] => Some(NeoClockMessage::Brightness {
+                brightness: payload.parse().ok()?,
+            }),
+            ["flyer", "set"] => Some(NeoClockMessage::Flyer {
+                id: self.first_widget("Flyer")?,
+                msg: FlyerMessage::new(payload, DEFAULT_FLYER_TTL),
+            }),
+            ["gif", "set"] => {
+                if !self.gifs.iter().any(|g| g == payload) {
+                    return None;
+                }
+                Some(NeoClockMessage::Gif {
+                    id: self.first_widget("Gif")?,
+                    msg: GifMessage {
+                        url: self.gif_dir.as_ref()?.join(payload).to_string_lossy().to_string(),
+                    },
+                })
+            }
+            ["scene", "set"] if self.scenes.iter().any(|s| s == payload) => Some(NeoClockMessage::Scene {
+                name: payload.to_owned(),
+            }),
+            ["widget", id, "set"] => {
+                let id: WidgetId = id.parse::<usize>().ok()?.into();
+                if parse_switch(payload)? {
+                    Some(NeoClockMessage::Show { id })
+                } else {
+                    Some(NeoClockMessage::Hide { id })
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse_switch(payload: &str) -> Option<bool> {
+    match payload {
+        "ON" => Some(true),
+        "OFF" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use renderer::Health;
+
+    use super::*;
+
+    fn widget(id: usize, kind: &'static str) -> WidgetStatus {
+        WidgetStatus {
+            id,
+            name: None,
+            kind,
+            group: None,
+            x: 0,
+            y: 0,
+            visible: true,
+            opacity: 1.0,
+            health: Health::Running,
+            dropped: 0,
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        let ha = HomeAssistant::new(
+            "homeassistant",
+            "clock",
+            "neoclock",
+            "neoclock/status",
+            "neoclock/availability",
+            vec![widget(0, "Solid"), widget(1, "Flyer")],
+            None,
+        );
+        let topics: Vec<String> = ha.discovery().into_iter().map(|(t, _)| t).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/light/clock/display/config",
+                "homeassistant/text/clock/message/config",
+                "homeassistant/switch/clock/widget_0/config",
+                "homeassistant/switch/clock/widget_1/config",
+            ]
+        );
+
+        assert!(matches!(
+            ha.translate("neoclock/ha/power/set", b"OFF"),
+            Some(NeoClockMessage::Power { on: false })
+        ));
+        assert!(matches!(
+            ha.translate("neoclock/ha/brightness/set", b"128"),
+            Some(NeoClockMessage::Brightness { brightness: 128 })
+        ));
+        assert!(matches!(
+            ha.translate("neoclock/ha/widget/0/set", b"OFF"),
+            Some(NeoClockMessage::Hide { id: WidgetId::Index(0) })
+        ));
+        match ha.translate("neoclock/ha/flyer/set", b"Build failed") {
+            Some(NeoClockMessage::Flyer { id, msg }) => {
+                assert_eq!(id, WidgetId::Index(1));
+                assert_eq!(msg.text, "Build failed");
+                assert_eq!(msg.ttl, DEFAULT_FLYER_TTL);
+            }
+            _ => panic!(),
+        }
+        // No Gif widget and no GIF directory
+        assert!(ha.translate("neoclock/ha/gif/set", b"robot.gif").is_none());
+        assert!(ha.translate("neoclock/ha/power/set", b"maybe").is_none());
+        // No scenes
+        assert!(ha.translate("neoclock/ha/scene/set", b"night").is_none());
+        assert!(ha.translate("neoclock/other", b"ON").is_none());
+    }
+
+    #[test]
+    fn test_scenes() {
+        let ha = HomeAssistant::new(
+            "homeassistant",
+            "clock",
+            "neoclock",
+            "neoclock/status",
+            "neoclock/availability",
+            vec![],
+            None,
+        )
+        .with_scenes(vec!["day".to_string(), "night".to_string()]);
+        let discovery = ha.discovery();
+        let (topic, select) = &discovery[1];
+        assert_eq!(topic, "homeassistant/select/clock/scene/config");
+        assert_eq!(select["command_topic"], "neoclock/ha/scene/set");
+        assert_eq!(select["options"], json!(["day", "night"]));
+
+        assert!(matches!(
+            ha.translate("neoclock/ha/scene/set", b"night"),
+            Some(NeoClockMessage::Scene { name }) if name == "night"
+        ));
+        assert!(ha.translate("neoclock/ha/scene/set", b"party").is_none());
+    }
+}
@@ -1,12 +1,28 @@
+mod acl;
+mod backoff;
+mod cli;
 mod config;
+mod config_file;
+mod homeassistant;
+mod mqtt;
+mod protocol;
+mod signing;
+mod state;
+mod status;
+mod topics;
+mod transport;
 
 use anyhow::Result;
-use log::{error, info, warn};
-use rumqttc::{Event, Outgoing, Packet};
-use std::{fs::File, io::BufReader, time::Duration};
-use structopt::StructOpt;
+use log::{info, warn};
+use std::time::Duration;
 
-use renderer::{Drawable, Screen, WidgetConf};
+use renderer::{Drawable, Screen};
+use transport::{
+    http::HttpTransport,
+    mqtt::MqttTransport,
+    socket::{SocketTransport, StdinTransport},
+    Transport,
+};
 
 #[derive(Clone, Debug, thiserror::Error)]
 #[error("{0}")]
@@ -36,63 +52,75 @@ use simulator::Matrix;
 async fn main() -> Result<()> {
     pretty_env_logger::init();
 
-    let opt = config::Config::from_args();
-    let screen = match &opt.config {
-        Some(s) => {
-            info!("Using config file at '{}'.", s);
-            let file = File::open(s)?;
-            let reader = BufReader::new(file);
-            let parts: Vec<WidgetConf> = serde_json::from_reader(reader)?;
-            Screen::new(64, 64, parts)
-        }
+    let mut opt = config::Config::load()?;
+    if let Some(cmd) = &opt.cmd {
+        return cli::run(&opt, cmd).await;
+    }
+
+    let screen = match opt.widgets.take() {
+        Some(parts) => Screen::new(64, 64, parts),
         None => Screen::default(),
     };
+    screen.set_compositing(opt.compositing);
+    screen.set_effects(std::mem::take(&mut opt.effects));
+    screen.set_scenes(std::mem::take(&mut opt.scenes));
+    if let Some(brightness) = opt.brightness {
+        screen.set_brightness(brightness);
+    }
+
+    let mut state_store = opt.state_file.clone().map(state::StateStore::new);
+    if let Some(saved) = state_store.as_mut().and_then(|s| s.load()) {
+        info!("Restoring the saved runtime state.");
+        if let Err(e) = screen.restore(&saved).await {
+            warn!("Can't restore the saved state, error is '{}'.", e);
+        }
+    }
 
     let mut matrix = Matrix::init()?;
     let mut canvas = matrix.get_canvas();
 
-    let mut receiver = opt.get_receiver().await?;
-    let sender = screen.sender.clone();
-
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()?;
 
-    rt.spawn(async move {
-        loop {
-            // NOTE:
-            // receiver.poll() blocks for few seconds every time, we need to move it to another seperated task (or thread?)
-            match receiver.poll().await {
-                // Ignore routine messages to avoid log flooding
-                Ok(Event::Incoming(Packet::PingResp)) => {}
-                Ok(Event::Outgoing(Outgoing::PingReq)) => {}
-                Ok(Event::Incoming(Packet::Publish(msg))) => {
-                    info!(
-                        "Got message: '{}({})'",
-                        &msg.topic,
-                        std::str::from_utf8(&msg.payload).unwrap()
-                    );
-                    if let Ok(m) =
-                        serde_json::from_slice::<renderer::message::NeoClockMessage>(&msg.payload)
-                    {
-                        sender.send(m).await.unwrap_or_default();
-                    } else {
-                        warn!("Received invalid message.");
-                    }
-                }
-                Ok(x) => {
-                    info!("Got unwanted message: '{:#?}'", x);
-                }
-                Err(e) => {
-                    error!("Connection error: '{:#?}'", e);
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                }
-            }
-        }
-    });
+    let acl = opt.get_acl()?;
+    let mut transports: Vec<Box<dyn Transport>> = vec![];
+    if opt.get_host().is_some() {
+        transports.push(Box::new(MqttTransport::new(&opt, &screen, acl.as_ref()).await?));
+    }
+    if let Some(addr) = opt.http {
+        transports.push(Box::new(HttpTransport::new(addr, screen.monitor())));
+    }
+    if let Some(path) = &opt.socket {
+        transports.push(Box::new(SocketTransport::new(path.clone())));
+    }
+    if opt.stdin {
+        transports.push(Box::new(StdinTransport));
+    }
+    let verifier = opt.get_verifier();
+    if verifier.is_some() {
+        info!("Message signing is enabled, unsigned messages are rejected.");
+    }
+    let dispatcher = protocol::Dispatcher::new(screen.sender.clone(), verifier, acl);
+    if transports.is_empty() {
+        info!("No control transport is configured, the screen is driven by the config file only.");
+    }
+    for t in transports.iter_mut() {
+        info!("Starting {} transport.", t.name());
+        t.start(&rt, dispatcher.clone())?;
+    }
 
+    let mut reporter = status::StatusReporter::new(opt.config.clone());
     loop {
         screen.render_to(&mut canvas);
+        if let Some(status) = reporter.frame(&screen) {
+            for t in transports.iter() {
+                t.status_changed(&status);
+            }
+        }
+        if let Some(store) = state_store.as_mut() {
+            store.frame(&screen);
+        }
         canvas = match matrix.swap(canvas) {
             Ok(c) => c,
             Err(_) => {
@@ -0,0 +1,197 @@
+use anyhow::bail;
+use rumqttc::{v5, QoS};
+
+/// The broker address, parsed from `host`, `host:port`, `[v6addr]:port`, `mqtt://host:port` or
+/// `mqtts://host:port`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Broker {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+}
+
+impl Broker {
+    /// `use_tls` is used when the address has no scheme
+    pub fn parse(s: &str, use_tls: bool) -> anyhow::Result<Broker> {
+        let (tls, rest) = match s.split_once("://") {
+            Some(("mqtt" | "tcp", rest)) => {
+                if use_tls {
+                    bail!("Broker URL '{}' doesn't use TLS, use 'mqtts://' instead.", s);
+                }
+                (false, rest)
+            }
+            Some(("mqtts" | "ssl", rest)) => (true, rest),
+            Some((scheme, _)) => bail!("Unsupported broker URL scheme '{}'.", scheme),
+            None => (use_tls, s),
+        };
+        // Paths are meaningless for MQTT over TCP
+        let authority = rest.split('/').next().unwrap_or_default();
+        let (host, port) = if let Some(v6) = authority.strip_prefix('[') {
+            match v6.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => match port.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => bail!("Invalid broker address '{}'.", s),
+                },
+                None => bail!("Invalid broker address '{}'.", s),
+            }
+        } else if authority.matches(':').count() > 1 {
+            // Bare IPv6 address without port
+            (authority, None)
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        if host.is_empty() {
+            bail!("Invalid broker address '{}'.", s);
+        }
+        let port = match port {
+            Some(port) => port.parse()?,
+            None if tls => 8883,
+            None => 1883,
+        };
+        Ok(Broker {
+            host: host.to_owned(),
+            port,
+            tls,
+        })
+    }
+}
+
+fn to_v5(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+/// MQTT client of either protocol version
+#[derive(Clone, Debug)]
+pub enum Client {
+    V4(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl Client {
+    pub async fn publish<S, P>(&self, topic: S, qos: QoS, retain: bool, payload: P) -> anyhow::Result<()>
+    where
+        S: Into<String>,
+        P: Into<Vec<u8>>,
+    {
+        let payload: Vec<u8> = payload.into();
+        match self {
+            Client::V4(c) => c.publish(topic, qos, retain, payload).await?,
+            Client::V5(c) => c.publish(topic, to_v5(qos), retain, payload).await?,
+        }
+        Ok(())
+    }
+
+    /// Publish without waiting, fails if the request queue is full
+    pub fn try_publish<S, P>(&self, topic: S, qos: QoS, retain: bool, payload: P) -> anyhow::Result<()>
+    where
+        S: Into<String>,
+        P: Into<Vec<u8>>,
+    {
+        let payload: Vec<u8> = payload.into();
+        match self {
+            Client::V4(c) => c.try_publish(topic, qos, retain, payload)?,
+            Client::V5(c) => c.try_publish(topic, to_v5(qos), retain, payload)?,
+        }
+        Ok(())
+    }
+
+    pub async fn subscribe_many(&self, filters: Vec<String>, qos: QoS) -> anyhow::Result<()> {
+        match self {
+            Client::V4(c) => {
+                c.subscribe_many(filters.into_iter().map(|f| rumqttc::SubscribeFilter::new(f, qos)))
+                    .await?
+            }
+            Client::V5(c) => {
+                c.subscribe_many(filters.into_iter().map(|f| v5::mqttbytes::v5::Filter::new(f, to_v5(qos))))
+                    .await?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The events the clock cares about, the same for both protocol versions
+#[derive(Debug)]
+pub enum Event {
+    Connected { session_present: bool },
+    Message { topic: String, payload: Vec<u8> },
+    /// Keep-alive traffic
+    Ping,
+    Other(String),
+}
+
+// There is only one event loop, the size doesn't matter
+#[allow(clippy::large_enum_variant)]
+pub enum EventLoop {
+    V4(rumqttc::EventLoop),
+    V5(v5::EventLoop),
+}
+
+impl EventLoop {
+    pub async fn poll(&mut self) -> anyhow::Result<Event> {
+        use rumqttc::{Event as E4, Outgoing, Packet as P4};
+        use v5::{mqttbytes::v5::Packet as P5, Event as E5};
+        Ok(match self {
+            EventLoop::V4(e) => match e.poll().await? {
+                E4::Incoming(P4::ConnAck(ack)) => Event::Connected {
+                    session_present: ack.session_present,
+                },
+                E4::Incoming(P4::Publish(msg)) => Event::Message {
+                    topic: msg.topic,
+                    payload: msg.payload.to_vec(),
+                },
+                E4::Incoming(P4::PingResp) | E4::Outgoing(Outgoing::PingReq) => Event::Ping,
+                x => Event::Other(format!("{:?}", x)),
+            },
+            EventLoop::V5(e) => match e.poll().await? {
+                E5::Incoming(P5::ConnAck(ack)) => Event::Connected {
+                    session_present: ack.session_present,
+                },
+                E5::Incoming(P5::Publish(msg)) => Event::Message {
+                    topic: String::from_utf8_lossy(&msg.topic).to_string(),
+                    payload: msg.payload.to_vec(),
+                },
+                E5::Incoming(P5::PingResp(_)) | E5::Outgoing(Outgoing::PingReq) => Event::Ping,
+                x => Event::Other(format!("{:?}", x)),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn broker(host: &str, port: u16, tls: bool) -> Broker {
+        Broker {
+            host: host.to_owned(),
+            port,
+            tls,
+        }
+    }
+
+    #[test]
+    fn test_parse_broker() {
+        assert_eq!(Broker::parse("localhost", false).unwrap(), broker("localhost", 1883, false));
+        assert_eq!(Broker::parse("localhost", true).unwrap(), broker("localhost", 8883, true));
+        assert_eq!(Broker::parse("10.0.0.1:1884", false).unwrap(), broker("10.0.0.1", 1884, false));
+        assert_eq!(Broker::parse("mqtt://broker/", false).unwrap(), broker("broker", 1883, false));
+        assert_eq!(Broker::parse("mqtts://broker:443", false).unwrap(), broker("broker", 443, true));
+        assert_eq!(Broker::parse("[::1]:1884", false).unwrap(), broker("::1", 1884, false));
+        assert_eq!(Broker::parse("mqtts://[fe80::1]", false).unwrap(), broker("fe80::1", 8883, true));
+        assert_eq!(Broker::parse("fe80::1", false).unwrap(), broker("fe80::1", 1883, false));
+        assert!(Broker::parse("http://broker", false).is_err());
+        assert!(Broker::parse("mqtt://broker", true).is_err());
+        assert!(Broker::parse("broker:port", false).is_err());
+        assert!(Broker::parse("[::1", false).is_err());
+        assert!(Broker::parse("mqtt://:1883", false).is_err());
+    }
+}
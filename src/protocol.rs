@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use log::warn;
+use renderer::message::{MessageError, MessageResult, MessageSender, NeoClockMessage, Request, Response};
+
+use crate::{
+    acl::{Acl, Source},
+    signing::Verifier,
+};
+
+/// Feeds messages from the transports to the screen, checking the signatures and the ACL first
+/// if they are enabled
+#[derive(Clone)]
+pub struct Dispatcher {
+    sender: MessageSender,
+    verifier: Option<Arc<Verifier>>,
+    acl: Option<Arc<Acl>>,
+}
+
+impl Dispatcher {
+    pub fn new(sender: MessageSender, verifier: Option<Verifier>, acl: Option<Acl>) -> Self {
+        Self {
+            sender,
+            verifier: verifier.map(Arc::new),
+            acl: acl.map(Arc::new),
+        }
+    }
+
+    /// Parse a JSON request and send it to the screen, returns the topic the response should be
+    /// published to, if the request asked for a reply
+    pub async fn dispatch(&self, source: &Source, payload: &[u8]) -> (Option<String>, Response) {
+        let verified;
+        let payload = match &self.verifier {
+            Some(verifier) => match verifier.verify(payload) {
+                Ok(p) => {
+                    verified = p;
+                    &verified
+                }
+                // Don't publish replies for messages that may come from anyone
+                Err(reason) => return (None, Response::new(None, Err(reject(reason)))),
+            },
+            None => payload,
+        };
+        match Request::from_slice(payload) {
+            Ok(req) => {
+                let result = self.send(source, req.message).await;
+                (req.reply_to, Response::new(req.request_id, result))
+            }
+            Err((response, reply_to)) => (reply_to, response),
+        }
+    }
+
+    /// Send a message translated from a payload that can't be signed, e.g. the per-widget topics,
+    /// they are rejected if signing is enabled
+    pub async fn send_unsigned(&self, source: &Source, msg: NeoClockMessage) -> MessageResult {
+        if self.verifier.is_some() {
+            return Err(reject("the message is not signed".to_string()));
+        }
+        self.send(source, msg).await
+    }
+
+    async fn send(&self, source: &Source, msg: NeoClockMessage) -> MessageResult {
+        if let Some(acl) = &self.acl {
+            if let Err(e) = acl.check(source, &msg) {
+                warn!("{}", e);
+                return Err(e);
+            }
+        }
+        self.sender.send(msg).await
+    }
+}
+
+fn reject(reason: String) -> MessageError {
+    let e = MessageError::Unauthorized { reason };
+    warn!("{}", e);
+    e
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use renderer::{message::WidgetId, Screen, WidgetConf};
+
+    use super::*;
+    use crate::signing;
+
+    #[tokio::test]
+    async fn test_signed_dispatch() {
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[{"type": "Solid", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"}]"#,
+        )
+        .unwrap();
+        let screen = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let dispatcher = Dispatcher::new(screen.sender.clone(), Some(Verifier::new(b"secret", Duration::from_secs(30))), None);
+
+        let payload = br#"{"type":"Hide","id":0,"request_id":"1","reply_to":"reply"}"#;
+        let (reply_to, response) = dispatcher.dispatch(&Source::Socket, payload).await;
+        assert_eq!(reply_to, None);
+        assert!(matches!(response.error, Some(MessageError::Unauthorized { .. })));
+        assert!(screen.status()[0].visible);
+
+        let (reply_to, response) = dispatcher.dispatch(&Source::Socket, &signing::sign(b"secret", payload)).await;
+        assert_eq!(reply_to.as_deref(), Some("reply"));
+        assert!(response.ok);
+        assert!(!screen.status()[0].visible);
+
+        let show = NeoClockMessage::Show { id: WidgetId::Index(0) };
+        assert!(matches!(
+            dispatcher.send_unsigned(&Source::Socket, show.clone()).await,
+            Err(MessageError::Unauthorized { .. })
+        ));
+        assert_eq!(Dispatcher::new(screen.sender.clone(), None, None).send_unsigned(&Source::Socket, show).await, Ok(()));
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A message signed with the shared secret, the signature is the hex encoded HMAC-SHA256 of
+/// `<timestamp>.<nonce>.<payload>`
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignedPayload {
+    /// The JSON message as a string
+    pub payload: String,
+    /// Seconds since the UNIX epoch
+    pub timestamp: u64,
+    /// Unique for every message
+    pub nonce: String,
+    pub signature: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn mac(key: &[u8], timestamp: u64, nonce: &str, payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}.{}.", timestamp, nonce).as_bytes());
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Sign `payload` with the current time and a random nonce
+pub fn sign(key: &[u8], payload: &[u8]) -> Vec<u8> {
+    sign_at(key, payload, now())
+}
+
+fn sign_at(key: &[u8], payload: &[u8], timestamp: u64) -> Vec<u8> {
+    let payload = String::from_utf8_lossy(payload).to_string();
+    let nonce = format!("{:032x}", rand::random::<u128>());
+    let signature = hex::encode(mac(key, timestamp, &nonce, &payload).finalize().into_bytes());
+    serde_json::to_vec(&SignedPayload {
+        payload,
+        timestamp,
+        nonce,
+        signature,
+    })
+    .unwrap_or_default()
+}
+
+/// Checks signed messages, a message is rejected if it's unsigned, the signature doesn't match,
+/// the timestamp is more than `max_age` away from now, or the nonce was seen before
+pub struct Verifier {
+    key: Vec<u8>,
+    max_age: u64,
+    /// Nonces seen in the last `max_age` seconds, older ones are rejected by the timestamp check
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl Verifier {
+    pub fn new(key: &[u8], max_age: Duration) -> Self {
+        Self {
+            key: key.to_vec(),
+            max_age: max_age.as_secs(),
+            seen: Default::default(),
+        }
+    }
+
+    /// Returns the signed payload, or the reason to reject the message
+    pub fn verify(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        self.verify_at(message, now())
+    }
+
+    fn verify_at(&self, message: &[u8], now: u64) -> Result<Vec<u8>, String> {
+        let signed: SignedPayload =
+            serde_json::from_slice(message).map_err(|_| "the message is not signed".to_string())?;
+        if signed.timestamp.abs_diff(now) > self.max_age {
+            return Err(format!("the message is stale, timestamp is {}", signed.timestamp));
+        }
+        let signature = hex::decode(&signed.signature).map_err(|_| "the signature is invalid".to_string())?;
+        mac(&self.key, signed.timestamp, &signed.nonce, &signed.payload)
+            .verify_slice(&signature)
+            .map_err(|_| "the signature is invalid".to_string())?;
+
+        let mut seen = self.seen.lock().map_err(|_| "the replay cache is broken".to_string())?;
+        seen.retain(|_, ts| ts.abs_diff(now) <= self.max_age);
+        if seen.insert(signed.nonce, signed.timestamp).is_some() {
+            return Err("the message is replayed".to_string());
+        }
+        Ok(signed.payload.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let verifier = Verifier::new(b"secret", Duration::from_secs(30));
+        let payload = br#"{"type":"Show","id":1}"#;
+
+        let signed = sign(b"secret", payload);
+        assert_eq!(verifier.verify(&signed).unwrap(), payload);
+        assert_eq!(verifier.verify(&signed).unwrap_err(), "the message is replayed");
+
+        assert_eq!(verifier.verify(payload).unwrap_err(), "the message is not signed");
+        assert_eq!(
+            verifier.verify(&sign(b"other", payload)).unwrap_err(),
+            "the signature is invalid"
+        );
+
+        let mut tampered: SignedPayload = serde_json::from_slice(&sign(b"secret", payload)).unwrap();
+        tampered.payload = r#"{"type":"Hide","id":1}"#.to_string();
+        let tampered = serde_json::to_vec(&tampered).unwrap();
+        assert_eq!(verifier.verify(&tampered).unwrap_err(), "the signature is invalid");
+
+        let signed = sign(b"secret", payload);
+        assert!(verifier.verify_at(&signed, now() + 31).unwrap_err().starts_with("the message is stale"));
+        assert!(verifier.verify_at(&signed, now() - 31).unwrap_err().starts_with("the message is stale"));
+        assert!(verifier.verify_at(&signed, now() + 10).is_ok());
+        assert_eq!(verifier.seen.lock().unwrap().len(), 2);
+        // Nonces are forgotten after `max_age`, by then the messages are stale anyway
+        assert!(verifier.verify_at(&sign_at(b"secret", payload, now() + 60), now() + 60).is_ok());
+        assert_eq!(verifier.seen.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use renderer::{RuntimeState, Screen};
+use tokio::sync::watch;
+
+/// How often the state is checked for changes
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Saves the runtime state of the screen when it changes, so it survives a restart
+pub struct StateStore {
+    path: PathBuf,
+    last_check: Option<Instant>,
+    last: Option<RuntimeState>,
+    /// The latest state to save, written by a background task started by the first save
+    writer: Option<watch::Sender<RuntimeState>>,
+}
+
+/// Written to a temporary file first, a crash never leaves a truncated state file behind
+fn save(path: &Path, state: &RuntimeState) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Saves the states off the render loop, a slow SD card would stall frames, states sent while a
+/// save is running are coalesced to the latest one
+async fn write_states(path: PathBuf, mut receiver: watch::Receiver<RuntimeState>) {
+    while receiver.changed().await.is_ok() {
+        let state = receiver.borrow_and_update().clone();
+        let path = path.clone();
+        let result = tokio::task::spawn_blocking(move || save(&path, &state).map_err(|e| (path, e))).await;
+        if let Ok(Err((path, e))) = result {
+            warn!("Can't save the state to '{}', error is '{}'.", path.display(), e);
+        }
+    }
+}
+
+impl StateStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            last_check: None,
+            last: None,
+            writer: None,
+        }
+    }
+
+    /// The saved state, `None` if there is no state file or it can't be read
+    pub fn load(&mut self) -> Option<RuntimeState> {
+        let bytes = match std::fs::read(&self.path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Can't read the state file at '{}', error is '{}'.", self.path.display(), e);
+                return None;
+            }
+        };
+        match serde_json::from_slice::<RuntimeState>(&bytes) {
+            Ok(state) => {
+                self.last = Some(state.clone());
+                Some(state)
+            }
+            Err(e) => {
+                warn!("Ignoring the state file at '{}', error is '{}'.", self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Called for every frame, saves the state if it has changed since the last save
+    pub fn frame(&mut self, screen: &Screen) {
+        if self.last_check.is_some_and(|t| t.elapsed() < SAVE_INTERVAL) {
+            return;
+        }
+        self.last_check = Some(Instant::now());
+        let state = screen.runtime_state();
+        if self.last.as_ref() == Some(&state) {
+            return;
+        }
+        match &self.writer {
+            Some(writer) => {
+                writer.send_replace(state.clone());
+            }
+            None => {
+                let (writer, mut receiver) = watch::channel(state.clone());
+                // The first state is saved too
+                receiver.mark_changed();
+                tokio::spawn(write_states(self.path.clone(), receiver));
+                self.writer = Some(writer);
+            }
+        }
+        // Not retried until the state changes again, a broken disk would flood the log otherwise
+        self.last = Some(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use renderer::WidgetConf;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("neoclock-state-{}.json", std::process::id()));
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[{"type": "Solid", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"}]"#,
+        )
+        .unwrap();
+        let screen = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut store = StateStore::new(path.clone());
+        assert_eq!(store.load(), None);
+        // Saved in the background
+        store.frame(&screen);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(StateStore::new(path.clone()).load(), Some(screen.runtime_state()));
+
+        screen.sender.send(renderer::message::NeoClockMessage::Hide { id: 0.into() }).await.unwrap();
+        // Not saved again before the next check
+        store.frame(&screen);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(StateStore::new(path.clone()).load().unwrap().widgets[0].visible);
+        store.last_check = None;
+        store.frame(&screen);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!StateStore::new(path.clone()).load().unwrap().widgets[0].visible);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+use renderer::{DisplayStatus, Screen, WidgetStatus};
+use serde::Serialize;
+
+/// How often the status is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Relative FPS change that is considered as a status change, smaller jitters are ignored
+const FPS_TOLERANCE: f64 = 0.1;
+
+/// The retained device status document
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub uptime: u64,
+    pub fps: f64,
+    pub config: Option<String>,
+    pub display: DisplayStatus,
+    pub widgets: Vec<WidgetStatus>,
+}
+
+pub struct StatusReporter {
+    started: Instant,
+    config: Option<String>,
+    window_start: Instant,
+    frames: u32,
+    last: Option<Status>,
+}
+
+impl StatusReporter {
+    pub fn new(config: Option<String>) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            config,
+            window_start: now,
+            frames: 0,
+            last: None,
+        }
+    }
+
+    /// Count a rendered frame, returns the new status if it has changed since the last time
+    pub fn frame(&mut self, screen: &Screen) -> Option<Status> {
+        self.frames += 1;
+        let elapsed = self.window_start.elapsed();
+        if elapsed < SAMPLE_INTERVAL {
+            return None;
+        }
+        let fps = (self.frames as f64 / elapsed.as_secs_f64() * 10.0).round() / 10.0;
+        self.frames = 0;
+        self.window_start = Instant::now();
+
+        let status = Status {
+            uptime: self.started.elapsed().as_secs(),
+            fps,
+            config: self.config.clone(),
+            display: screen.display_status(),
+            widgets: screen.status(),
+        };
+        let changed = match &self.last {
+            Some(last) => {
+                last.widgets != status.widgets
+                    || last.display != status.display
+                    || (last.fps - status.fps).abs() > last.fps * FPS_TOLERANCE
+            }
+            None => true,
+        };
+        if changed {
+            self.last = Some(status.clone());
+            Some(status)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use renderer::{message::NeoClockMessage, WidgetConf};
+    use serde_json::json;
+
+    use super::*;
+
+    /// A frame at the end of a sample window of `fps` frames
+    fn sample(reporter: &mut StatusReporter, screen: &Screen, fps: u32) -> Option<Status> {
+        reporter.frames = fps - 1;
+        reporter.window_start = Instant::now() - SAMPLE_INTERVAL;
+        reporter.frame(screen)
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[{"type": "Solid", "name": "bg", "x": 2, "y": 3, "width": 8, "height": 8, "color": "red"}]"#,
+        )
+        .unwrap();
+        let screen = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut reporter = StatusReporter::new(Some("config.json".to_string()));
+
+        // Nothing before the end of the sample window
+        assert!(reporter.frame(&screen).is_none());
+        let status = sample(&mut reporter, &screen, 60).unwrap();
+        assert_eq!(status.fps, 60.0);
+        let doc = serde_json::to_value(&status).unwrap();
+        assert_eq!(doc["config"], "config.json");
+        assert_eq!(
+            doc["widgets"][0],
+            json!({"id": 0, "name": "bg", "type": "Solid", "x": 2, "y": 3, "visible": true, "opacity": 1.0,
+                "health": "running", "dropped": 0})
+        );
+
+        // Unchanged, and FPS jitter in the tolerance
+        assert!(sample(&mut reporter, &screen, 60).is_none());
+        assert!(sample(&mut reporter, &screen, 64).is_none());
+        assert!(sample(&mut reporter, &screen, 57).is_none());
+        assert_eq!(sample(&mut reporter, &screen, 30).unwrap().fps, 30.0);
+
+        screen.sender.send(NeoClockMessage::Hide { id: 0.into() }).await.unwrap();
+        let status = sample(&mut reporter, &screen, 30).unwrap();
+        assert!(!status.widgets[0].visible);
+        assert!(sample(&mut reporter, &screen, 30).is_none());
+    }
+}
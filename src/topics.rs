@@ -0,0 +1,192 @@
+use renderer::{
+    message::{MessageError, MoveMessage, NeoClockMessage, WidgetId},
+    WidgetStatus, DEFAULT_FLYER_TTL,
+};
+use serde_json::{Map, Value};
+
+/// Per-widget topics, `<topic>/widget/<name>/{set,visible,move}`, for clients that can't build
+/// the tagged JSON envelope
+pub struct WidgetTopics {
+    base: String,
+    widgets: Vec<WidgetStatus>,
+}
+
+impl WidgetTopics {
+    pub fn new(topic: &str, widgets: Vec<WidgetStatus>) -> Self {
+        Self {
+            base: format!("{}/widget", topic),
+            widgets,
+        }
+    }
+
+    pub fn filters(&self) -> Vec<String> {
+        ["set", "visible", "move"]
+            .iter()
+            .map(|t| format!("{}/+/{}", self.base, t))
+            .collect()
+    }
+
+    /// Translate a message published to a widget topic, returns `None` if `topic` is not one of
+    /// the widget topics
+    pub fn translate(
+        &self,
+        topic: &str,
+        payload: &[u8],
+    ) -> Option<Result<NeoClockMessage, MessageError>> {
+        let (widget, action) = topic
+            .strip_prefix(&self.base)?
+            .strip_prefix('/')?
+            .split_once('/')?;
+        let id: WidgetId = widget.parse().unwrap_or_else(|e| match e {});
+        let payload = String::from_utf8_lossy(payload);
+        let payload = payload.trim();
+        match action {
+            "set" => Some(self.translate_set(id, payload)),
+            "visible" => Some(translate_visible(id, payload)),
+            "move" => Some(translate_move(id, payload)),
+            _ => None,
+        }
+    }
+
+    /// The payload is the widget specific message, a plain string is taken as the main field of
+    /// the message, e.g. the text of a Flyer message
+    fn translate_set(&self, id: WidgetId, payload: &str) -> Result<NeoClockMessage, MessageError> {
+        let widget = self
+            .widgets
+            .iter()
+            .find(|w| w.matches(&id))
+            .ok_or_else(|| MessageError::UnknownWidget { id: id.clone() })?;
+        let mut fields = match serde_json::from_str::<Value>(payload) {
+            Ok(Value::Object(fields)) => fields,
+            Ok(Value::String(s)) => plain_fields(widget.kind, &s),
+            _ => plain_fields(widget.kind, payload),
+        };
+        fields.insert("type".to_string(), Value::from(widget.kind));
+        fields.insert("id".to_string(), Value::from(widget.id));
+        serde_json::from_value(Value::Object(fields)).map_err(|e| MessageError::Parse {
+            message: e.to_string(),
+        })
+    }
+}
+
+fn plain_fields(kind: &str, s: &str) -> Map<String, Value> {
+    let mut fields = Map::new();
+    match kind {
+        "Flyer" => {
+            fields.insert("text".to_string(), Value::from(s));
+            fields.insert("ttl".to_string(), Value::from(DEFAULT_FLYER_TTL));
+        }
+        "Gif" => {
+            fields.insert("url".to_string(), Value::from(s));
+        }
+        "Solid" => {
+            fields.insert("color".to_string(), Value::from(s));
+        }
+        _ => {}
+    }
+    fields
+}
+
+fn translate_visible(id: WidgetId, payload: &str) -> Result<NeoClockMessage, MessageError> {
+    match payload.to_lowercase().as_str() {
+        "true" | "on" | "1" | "show" => Ok(NeoClockMessage::Show { id }),
+        "false" | "off" | "0" | "hide" => Ok(NeoClockMessage::Hide { id }),
+        _ => Err(MessageError::Parse {
+            message: format!("'{}' is not a visibility", payload),
+        }),
+    }
+}
+
+/// The payload is either `{"x": 1, "y": 2}` or `1,2`
+fn translate_move(id: WidgetId, payload: &str) -> Result<NeoClockMessage, MessageError> {
+    #[derive(serde::Deserialize)]
+    struct Position {
+        x: u32,
+        y: u32,
+    }
+    let position = serde_json::from_str::<Position>(payload).ok().or_else(|| {
+        let (x, y) = payload.split_once(',')?;
+        Some(Position {
+            x: x.trim().parse().ok()?,
+            y: y.trim().parse().ok()?,
+        })
+    });
+    match position {
+        Some(Position { x, y }) => Ok(NeoClockMessage::Move(MoveMessage { id, x, y })),
+        None => Err(MessageError::Parse {
+            message: format!("'{}' is not a position", payload),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use renderer::Health;
+
+    use super::*;
+
+    fn widget(id: usize, name: &str, kind: &'static str) -> WidgetStatus {
+        WidgetStatus {
+            id,
+            name: Some(name.to_string()),
+            kind,
+            group: None,
+            x: 0,
+            y: 0,
+            visible: true,
+            opacity: 1.0,
+            health: Health::Running,
+            dropped: 0,
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        let topics = WidgetTopics::new(
+            "neoclock",
+            vec![widget(0, "bg", "Solid"), widget(1, "news", "Flyer")],
+        );
+
+        match topics.translate("neoclock/widget/news/set", b"Build failed") {
+            Some(Ok(NeoClockMessage::Flyer { id, msg })) => {
+                assert_eq!(id, WidgetId::Index(1));
+                assert_eq!(msg.text, "Build failed");
+                assert_eq!(msg.ttl, DEFAULT_FLYER_TTL);
+            }
+            _ => panic!(),
+        }
+        match topics.translate("neoclock/widget/1/set", br#"{"text":"Hi","ttl":5}"#) {
+            Some(Ok(NeoClockMessage::Flyer { msg, .. })) => assert_eq!(msg.ttl, 5),
+            _ => panic!(),
+        }
+        assert!(matches!(
+            topics.translate("neoclock/widget/bg/set", b"rgb(1,2,3)"),
+            Some(Ok(NeoClockMessage::Solid { .. }))
+        ));
+        assert!(matches!(
+            topics.translate("neoclock/widget/bg/set", b"not a color"),
+            Some(Err(MessageError::Parse { .. }))
+        ));
+        assert!(matches!(
+            topics.translate("neoclock/widget/nope/set", b"Hi"),
+            Some(Err(MessageError::UnknownWidget { .. }))
+        ));
+
+        match topics.translate("neoclock/widget/bg/visible", b"OFF") {
+            Some(Ok(NeoClockMessage::Hide { id })) => assert_eq!(id, WidgetId::from("bg")),
+            _ => panic!(),
+        }
+        match topics.translate("neoclock/widget/2/move", b"10, 20") {
+            Some(Ok(NeoClockMessage::Move(m))) => {
+                assert_eq!(m.id, WidgetId::Index(2));
+                assert_eq!((m.x, m.y), (10, 20));
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(
+            topics.translate("neoclock/widget/bg/move", br#"{"x":1,"y":2}"#),
+            Some(Ok(NeoClockMessage::Move(_)))
+        ));
+        assert!(topics.translate("neoclock", b"{}").is_none());
+    }
+}
@@ -0,0 +1,390 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    body::HttpBody,
+    header::{CONNECTION, CONTENT_TYPE, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{error, info, warn};
+use renderer::{
+    message::{MessageError, MoveMessage, NeoClockMessage, WidgetId},
+    ScreenMonitor, WidgetStatus,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{runtime::Runtime, sync::watch};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role},
+    WebSocketStream,
+};
+
+use super::{websocket, Transport};
+use crate::{acl::Source, protocol::Dispatcher, status::Status};
+
+/// Requests with larger bodies are rejected
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Fields of a widget that can be changed with `PATCH /widgets/{id}`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WidgetPatch {
+    visible: Option<bool>,
+    x: Option<u32>,
+    y: Option<u32>,
+}
+
+pub(super) struct Context {
+    pub(super) dispatcher: Dispatcher,
+    pub(super) monitor: ScreenMonitor,
+    pub(super) status: watch::Receiver<Option<Status>>,
+}
+
+/// REST API, `POST /messages`, `GET /widgets`, `GET /widgets/{id}`, `PATCH /widgets/{id}` and
+/// `GET /frame.png`, plus the WebSocket endpoint `GET /ws`
+pub struct HttpTransport {
+    addr: SocketAddr,
+    monitor: ScreenMonitor,
+    status: watch::Sender<Option<Status>>,
+}
+
+impl HttpTransport {
+    pub fn new(addr: SocketAddr, monitor: ScreenMonitor) -> Self {
+        Self {
+            addr,
+            monitor,
+            status: watch::channel(None).0,
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn start(&mut self, rt: &Runtime, dispatcher: Dispatcher) -> anyhow::Result<()> {
+        let ctx = Arc::new(Context {
+            dispatcher,
+            monitor: self.monitor.clone(),
+            status: self.status.subscribe(),
+        });
+        let make_svc = make_service_fn(move |_| {
+            let ctx = ctx.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(ctx.clone(), req))) }
+        });
+        // Binding needs the runtime, do it here so a bad address fails the startup
+        let _guard = rt.enter();
+        let server = Server::try_bind(&self.addr)?.serve(make_svc);
+        // The actual port if it was 0
+        self.addr = server.local_addr();
+        info!("HTTP API is listening on {}.", self.addr);
+        rt.spawn(async move {
+            if let Err(e) = server.await {
+                error!("HTTP server stopped, error is '{}'.", e);
+            }
+        });
+        Ok(())
+    }
+
+    fn status_changed(&self, status: &Status) {
+        self.status.send_replace(Some(status.clone()));
+    }
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(body).unwrap_or_default()))
+        .unwrap_or_default()
+}
+
+fn status_code(e: &MessageError) -> StatusCode {
+    match e {
+        MessageError::Parse { .. } => StatusCode::BAD_REQUEST,
+        MessageError::UnknownWidget { .. } | MessageError::UnknownScene { .. } => StatusCode::NOT_FOUND,
+        MessageError::WrongWidgetType { .. } | MessageError::InvalidConfig { .. } => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        MessageError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+        MessageError::Forbidden { .. } => StatusCode::FORBIDDEN,
+        MessageError::QueueFull { .. } => StatusCode::TOO_MANY_REQUESTS,
+        MessageError::Closed => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+fn error_response(e: MessageError) -> Response<Body> {
+    json(status_code(&e), &e)
+}
+
+async fn read_body(body: &mut Body) -> Result<Vec<u8>, Response<Body>> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            error_response(MessageError::Parse {
+                message: e.to_string(),
+            })
+        })?;
+        if buf.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Body::empty())
+                .unwrap_or_default());
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+fn find_widget(ctx: &Context, id: &WidgetId) -> Result<WidgetStatus, MessageError> {
+    ctx.monitor
+        .status()
+        .into_iter()
+        .find(|w| w.matches(id))
+        .ok_or_else(|| MessageError::UnknownWidget { id: id.clone() })
+}
+
+async fn patch_widget(ctx: &Context, id: WidgetId, body: &[u8]) -> Result<Response<Body>, Response<Body>> {
+    let widget = find_widget(ctx, &id).map_err(error_response)?;
+    let patch: WidgetPatch = serde_json::from_slice(body).map_err(|e| {
+        error_response(MessageError::Parse {
+            message: e.to_string(),
+        })
+    })?;
+    let id = WidgetId::Index(widget.id);
+    let mut messages = vec![];
+    if patch.x.is_some() || patch.y.is_some() {
+        messages.push(NeoClockMessage::Move(MoveMessage {
+            id: id.clone(),
+            x: patch.x.unwrap_or(widget.x),
+            y: patch.y.unwrap_or(widget.y),
+        }));
+    }
+    match patch.visible {
+        Some(true) => messages.push(NeoClockMessage::Show { id: id.clone() }),
+        Some(false) => messages.push(NeoClockMessage::Hide { id: id.clone() }),
+        None => {}
+    }
+    // One batch, no frame shows half of the patch and nothing is applied if a message fails
+    if !messages.is_empty() {
+        let batch = NeoClockMessage::Batch { messages };
+        ctx.dispatcher.send_unsigned(&Source::Http, batch).await.map_err(error_response)?;
+    }
+    Ok(json(StatusCode::OK, &find_widget(ctx, &id).map_err(error_response)?))
+}
+
+fn upgrade_websocket(ctx: &Arc<Context>, req: &mut Request<Body>) -> Response<Body> {
+    let is_websocket = req
+        .headers()
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or_default();
+    let key = match req.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) if is_websocket => key.as_bytes(),
+        _ => {
+            return Response::builder()
+                .status(StatusCode::UPGRADE_REQUIRED)
+                .body(Body::empty())
+                .unwrap_or_default()
+        }
+    };
+    let accept = derive_accept_key(key);
+    let frames = websocket::frame_interval(req.uri().query());
+    let upgrade = hyper::upgrade::on(req);
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => {
+                info!("WebSocket client connected.");
+                let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                websocket::serve(ctx, ws, frames).await;
+            }
+            Err(e) => warn!("WebSocket upgrade failed, error is '{}'.", e),
+        }
+    });
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap_or_default()
+}
+
+async fn route(ctx: &Arc<Context>, req: &mut Request<Body>) -> Result<Response<Body>, Response<Body>> {
+    let path: Vec<&str> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    match (req.method(), path.as_slice()) {
+        (&Method::POST, ["messages"]) => {
+            let body = read_body(req.body_mut()).await?;
+            // Replies go back in the HTTP response, `reply_to` is ignored
+            let (_, response) = ctx.dispatcher.dispatch(&Source::Http, &body).await;
+            let status = match &response.error {
+                Some(e) => status_code(e),
+                None => StatusCode::OK,
+            };
+            Ok(json(status, &response))
+        }
+        (&Method::GET, ["widgets"]) => Ok(json(StatusCode::OK, &ctx.monitor.status())),
+        (&Method::GET, ["widgets", id]) => {
+            let id: WidgetId = id.parse().unwrap_or_else(|e| match e {});
+            Ok(json(StatusCode::OK, &find_widget(ctx, &id).map_err(error_response)?))
+        }
+        (&Method::PATCH, ["widgets", id]) => {
+            let id: WidgetId = id.parse().unwrap_or_else(|e| match e {});
+            let body = read_body(req.body_mut()).await?;
+            patch_widget(ctx, id, &body).await
+        }
+        (&Method::GET, ["frame.png"]) => {
+            let png = ctx.monitor.frame_png().map_err(|e| {
+                error!("Failed to encode frame, error is '{}'.", e);
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+                    .unwrap_or_default()
+            })?;
+            Ok(Response::builder()
+                .header(CONTENT_TYPE, "image/png")
+                .body(Body::from(png))
+                .unwrap_or_default())
+        }
+        (&Method::GET, ["ws"]) => Ok(upgrade_websocket(ctx, req)),
+        (_, ["messages"] | ["widgets"] | ["widgets", _] | ["frame.png"] | ["ws"]) => Err(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+            .unwrap_or_default()),
+        _ => Err(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap_or_default()),
+    }
+}
+
+async fn handle(ctx: Arc<Context>, mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    info!("HTTP request: {} {}", req.method(), req.uri());
+    Ok(route(&ctx, &mut req).await.unwrap_or_else(|e| e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use renderer::{Screen, WidgetConf};
+
+    use super::*;
+
+    async fn call(ctx: &Arc<Context>, method: Method, uri: &str, body: &str) -> (StatusCode, serde_json::Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let res = handle(ctx.clone(), req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_http() {
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[
+            {
+                "type": "Solid",
+                "name": "bg",
+                "x": 0,
+                "y": 0,
+                "width": 8,
+                "height": 8,
+                "color": "rgb(255,0,0)"
+            }
+        ]"#,
+        )
+        .unwrap();
+        let screen = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let ctx = Arc::new(Context {
+            dispatcher: Dispatcher::new(screen.sender.clone(), None, None),
+            monitor: screen.monitor(),
+            status: watch::channel(None).1,
+        });
+
+        let (status, body) = call(&ctx, Method::GET, "/widgets", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["name"], "bg");
+
+        let (status, body) = call(&ctx, Method::PATCH, "/widgets/bg", r#"{"visible":false,"x":4}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["visible"], false);
+        assert_eq!(body["x"], 4);
+        assert_eq!(body["y"], 0);
+
+        let (status, body) = call(&ctx, Method::POST, "/messages", r#"{"type":"Show","id":0,"request_id":"1"}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["request_id"], "1");
+        let (_, body) = call(&ctx, Method::GET, "/widgets/0", "").await;
+        assert_eq!(body["visible"], true);
+
+        let (status, body) = call(&ctx, Method::GET, "/widgets/nope", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["kind"], "unknown_widget");
+        let (status, _) = call(&ctx, Method::POST, "/messages", "{").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(&ctx, Method::DELETE, "/widgets/0", "").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+
+        let res = handle(ctx.clone(), Request::get("/frame.png").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[CONTENT_TYPE], "image/png");
+    }
+
+    #[test]
+    fn test_websocket() {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let rt = Runtime::new().unwrap();
+        let screen = rt.block_on(async {
+            let parts: Vec<WidgetConf> = serde_json::from_str(
+                r#"[{"type": "Solid", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"}]"#,
+            )
+            .unwrap();
+            Screen::new(64, 64, parts)
+        });
+        let mut transport = HttpTransport::new("127.0.0.1:0".parse().unwrap(), screen.monitor());
+        transport.start(&rt, Dispatcher::new(screen.sender.clone(), None, None)).unwrap();
+        let url = format!("ws://{}/ws?frames=10", transport.addr);
+
+        rt.block_on(async {
+            let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            ws.send(Message::Text(r#"{"type":"Hide","id":0,"request_id":"a"}"#.into()))
+                .await
+                .unwrap();
+            let mut got_ack = false;
+            let mut got_frame = false;
+            while !(got_ack && got_frame) {
+                match ws.next().await.unwrap().unwrap() {
+                    Message::Text(text) => {
+                        let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+                        assert_eq!(event["event"], "ack");
+                        assert_eq!(event["request_id"], "a");
+                        assert_eq!(event["ok"], true);
+                        got_ack = true;
+                    }
+                    Message::Binary(png) => {
+                        assert!(png.starts_with(b"\x89PNG"));
+                        got_frame = true;
+                    }
+                    _ => {}
+                }
+            }
+        });
+        rt.shutdown_background();
+    }
+}
@@ -0,0 +1,22 @@
+use tokio::runtime::Runtime;
+
+use crate::{protocol::Dispatcher, status::Status};
+
+pub mod http;
+pub mod mqtt;
+pub mod socket;
+mod websocket;
+
+/// A control-plane transport, receives messages from outside and feeds them to the screen.
+///
+/// All transports are optional, a clock with none of them enabled only shows what's in the config
+/// file and never touches the network.
+pub trait Transport {
+    fn name(&self) -> &'static str;
+
+    /// Start serving in background tasks on `rt`
+    fn start(&mut self, rt: &Runtime, dispatcher: Dispatcher) -> anyhow::Result<()>;
+
+    /// Called by the render loop when the device status has changed
+    fn status_changed(&self, _status: &Status) {}
+}
@@ -0,0 +1,187 @@
+use log::{error, info, warn};
+use renderer::{message::MessageError, Screen};
+use rumqttc::QoS;
+use tokio::runtime::Runtime;
+
+use super::Transport;
+use crate::{
+    backoff::Backoff,
+    config::Config,
+    homeassistant::HomeAssistant,
+    mqtt::{Client, Event, EventLoop},
+    acl::{Acl, Source},
+    protocol::Dispatcher,
+    status::Status,
+    topics::WidgetTopics,
+};
+
+/// State moved into the receiving task when the transport starts
+struct Receiver {
+    eventloop: EventLoop,
+    subscriptions: Vec<String>,
+    ha: Option<HomeAssistant>,
+    widget_topics: WidgetTopics,
+}
+
+/// Messages on `<topic>`, the per-widget topics and Home Assistant commands, publishes the device
+/// status and availability
+pub struct MqttTransport {
+    client: Client,
+    qos: QoS,
+    status_topic: String,
+    availability_topic: String,
+    receiver: Option<Receiver>,
+}
+
+impl MqttTransport {
+    /// The topics in the ACL rules are subscribed too
+    pub async fn new(opt: &Config, screen: &Screen, acl: Option<&Acl>) -> anyhow::Result<Self> {
+        let (client, eventloop) = opt.get_receiver().await?;
+        let availability_topic = opt.get_availability_topic();
+        let mut subscriptions = vec![opt.topic.clone()];
+        if let Some(acl) = acl {
+            subscriptions.extend(acl.topics().filter(|t| *t != opt.topic).map(|t| t.to_owned()));
+        }
+
+        let ha = if opt.homeassistant {
+            let ha = HomeAssistant::new(
+                &opt.ha_prefix,
+                &opt.get_device_id(),
+                &opt.topic,
+                &opt.get_status_topic(),
+                &availability_topic,
+                screen.status(),
+                opt.ha_gif_dir.as_deref(),
+            )
+            .with_scenes(screen.scene_names());
+            subscriptions.push(ha.command_filter());
+            Some(ha)
+        } else {
+            None
+        };
+
+        let widget_topics = WidgetTopics::new(&opt.topic, screen.status());
+        subscriptions.extend(widget_topics.filters());
+
+        Ok(Self {
+            client,
+            qos: opt.qos,
+            status_topic: opt.get_status_topic(),
+            availability_topic,
+            receiver: Some(Receiver {
+                eventloop,
+                subscriptions,
+                ha,
+                widget_topics,
+            }),
+        })
+    }
+}
+
+impl Transport for MqttTransport {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn start(&mut self, rt: &Runtime, dispatcher: Dispatcher) -> anyhow::Result<()> {
+        let Receiver {
+            mut eventloop,
+            subscriptions,
+            ha,
+            widget_topics,
+        } = match self.receiver.take() {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        let discovery: Vec<(String, String)> = ha
+            .as_ref()
+            .map(|ha| ha.discovery().into_iter().map(|(t, p)| (t, p.to_string())).collect())
+            .unwrap_or_default();
+
+        let qos = self.qos;
+        let availability_topic = self.availability_topic.clone();
+        let reply_client = self.client.clone();
+        rt.spawn(async move {
+            let mut backoff = Backoff::new();
+            loop {
+                // NOTE:
+                // receiver.poll() blocks for few seconds every time, we need to move it to another seperated task (or thread?)
+                match eventloop.poll().await {
+                    // Ignore routine messages to avoid log flooding
+                    Ok(Event::Ping) => {}
+                    Ok(Event::Connected { session_present }) => {
+                        info!("Connected to MQTT broker, session present: {}.", session_present);
+                        backoff.reset();
+                        // The broker may have lost the session, subscribe again on every connection.
+                        // Requests are queued to the event loop, which is this task, so don't wait here
+                        let client = reply_client.clone();
+                        let subscriptions = subscriptions.clone();
+                        let availability_topic = availability_topic.clone();
+                        // Published on every connection too, a restarted broker may have lost
+                        // the retained discovery
+                        let discovery = discovery.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = client.subscribe_many(subscriptions, qos).await {
+                                warn!("Failed to subscribe, error is '{}'.", e);
+                            }
+                            for (topic, payload) in discovery {
+                                if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, payload).await {
+                                    warn!("Failed to publish Home Assistant discovery, error is '{}'.", e);
+                                }
+                            }
+                            if let Err(e) = client.publish(availability_topic, QoS::AtLeastOnce, true, "online").await {
+                                warn!("Failed to publish availability, error is '{}'.", e);
+                            }
+                        });
+                    }
+                    Ok(Event::Message { topic, payload }) => {
+                        info!("Got message: '{}({})'", &topic, String::from_utf8_lossy(&payload));
+                        let source = Source::Mqtt(topic.clone());
+                        if let Some(m) = ha.as_ref().and_then(|ha| ha.translate(&topic, &payload)) {
+                            if let Err(e) = dispatcher.send_unsigned(&source, m).await {
+                                warn!("Home Assistant command on '{}' rejected, {}", topic, e);
+                            }
+                            continue;
+                        }
+                        if let Some(m) = widget_topics.translate(&topic, &payload) {
+                            if let Err(e) = match m {
+                                Ok(m) => dispatcher.send_unsigned(&source, m).await,
+                                Err(e) => Err(e),
+                            } {
+                                warn!("Message on '{}' rejected, {}", topic, e);
+                            }
+                            continue;
+                        }
+                        let (reply_to, response) = dispatcher.dispatch(&source, &payload).await;
+                        if let Some(e @ MessageError::Parse { .. }) = &response.error {
+                            warn!("Received invalid message, {}", e);
+                        }
+                        if let Some(topic) = reply_to {
+                            let payload = serde_json::to_vec(&response).unwrap_or_default();
+                            if let Err(e) = reply_client.try_publish(topic, QoS::AtLeastOnce, false, payload) {
+                                warn!("Failed to publish reply, error is '{}'.", e);
+                            }
+                        }
+                    }
+                    Ok(Event::Other(x)) => {
+                        info!("Got unwanted message: '{}'", x);
+                    }
+                    Err(e) => {
+                        let delay = backoff.next_delay();
+                        error!("Connection error: '{:#}', retrying in {:.1}s", e, delay.as_secs_f64());
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn status_changed(&self, status: &Status) {
+        let payload = serde_json::to_vec(status).unwrap_or_default();
+        if let Err(e) = self.client.try_publish(&self.status_topic, QoS::AtLeastOnce, true, payload) {
+            warn!("Failed to publish status, error is '{}'.", e);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::{os::unix::fs::FileTypeExt, path::PathBuf};
+
+use log::{error, info, warn};
+use renderer::message::MessageError;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::UnixListener,
+    runtime::Runtime,
+};
+
+use super::Transport;
+use crate::{acl::Source, protocol::Dispatcher};
+
+/// Read newline-delimited JSON messages from `reader`, and write one JSON response per line to
+/// `writer`, until `reader` reaches EOF
+async fn serve_lines<R, W>(dispatcher: &Dispatcher, source: &Source, reader: R, mut writer: W) -> std::io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = reader.lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Replies go back on the same stream, `reply_to` is ignored
+        let (_, response) = dispatcher.dispatch(source, line.as_bytes()).await;
+        if let Some(e @ MessageError::Parse { .. }) = &response.error {
+            warn!("Received invalid message, {}", e);
+        }
+        let mut reply = serde_json::to_vec(&response).unwrap_or_default();
+        reply.push(b'\n');
+        writer.write_all(&reply).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// JSON lines on a Unix domain socket
+pub struct SocketTransport {
+    path: PathBuf,
+}
+
+impl SocketTransport {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Transport for SocketTransport {
+    fn name(&self) -> &'static str {
+        "socket"
+    }
+
+    fn start(&mut self, rt: &Runtime, dispatcher: Dispatcher) -> anyhow::Result<()> {
+        // Remove the socket left by the last run, but never anything else
+        if let Ok(meta) = std::fs::symlink_metadata(&self.path) {
+            if meta.file_type().is_socket() {
+                std::fs::remove_file(&self.path)?;
+            }
+        }
+        let _guard = rt.enter();
+        let listener = UnixListener::bind(&self.path)?;
+        info!("Listening on socket '{}'.", self.path.display());
+        rt.spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let dispatcher = dispatcher.clone();
+                        tokio::spawn(async move {
+                            let (reader, writer) = stream.into_split();
+                            if let Err(e) = serve_lines(&dispatcher, &Source::Socket, BufReader::new(reader), writer).await {
+                                warn!("Socket connection closed, error is '{}'.", e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        error!("Failed to accept socket connection, error is '{}'.", e);
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// JSON lines on stdin, replies go to stdout
+pub struct StdinTransport;
+
+impl Transport for StdinTransport {
+    fn name(&self) -> &'static str {
+        "stdin"
+    }
+
+    fn start(&mut self, rt: &Runtime, dispatcher: Dispatcher) -> anyhow::Result<()> {
+        rt.spawn(async move {
+            let stdin = BufReader::new(tokio::io::stdin());
+            match serve_lines(&dispatcher, &Source::Stdin, stdin, tokio::io::stdout()).await {
+                Ok(_) => info!("Stdin is closed."),
+                Err(e) => error!("Failed to read stdin, error is '{}'.", e),
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use renderer::{Screen, WidgetConf};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve_lines() {
+        let parts: Vec<WidgetConf> = serde_json::from_str(
+            r#"[{"type": "Solid", "x": 0, "y": 0, "width": 8, "height": 8, "color": "red"}]"#,
+        )
+        .unwrap();
+        let screen = Screen::new(64, 64, parts);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let input = b"{\"type\":\"Hide\",\"id\":0,\"request_id\":\"1\"}\n\n{\"type\":\"Show\",\"id\":9}\nnot json\n";
+        let mut output: Vec<u8> = vec![];
+        serve_lines(&Dispatcher::new(screen.sender.clone(), None, None), &Source::Socket, &input[..], &mut output).await.unwrap();
+
+        let replies: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["request_id"], "1");
+        assert_eq!(replies[0]["ok"], true);
+        assert_eq!(replies[1]["error"]["kind"], "unknown_widget");
+        assert_eq!(replies[2]["error"]["kind"], "parse");
+        assert!(!screen.status()[0].visible);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use hyper::upgrade::Upgraded;
+use log::{info, warn};
+use renderer::message::Response;
+use serde::Serialize;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+use super::http::Context;
+use crate::{acl::Source, status::Status};
+
+/// Upper bound of the frame rate a client can ask for
+const MAX_FRAME_RATE: u32 = 30;
+
+/// Events pushed to WebSocket clients as JSON text messages, frames are sent as binary PNG
+/// messages
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum WsEvent<'a> {
+    /// The result of a message sent by the client
+    Ack(&'a Response),
+    /// The device status, sent on connect and whenever it changes
+    Status(&'a Status),
+}
+
+impl WsEvent<'_> {
+    fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+/// Frame interval asked by the client with `?frames=<fps>`, frames are not pushed by default
+pub(super) fn frame_interval(query: Option<&str>) -> Option<Duration> {
+    let fps: u32 = query?
+        .split('&')
+        .find_map(|kv| kv.strip_prefix("frames="))?
+        .parse()
+        .ok()?;
+    if fps == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(1) / fps.min(MAX_FRAME_RATE))
+}
+
+/// Serve a WebSocket connection until the client goes away
+pub(super) async fn serve(ctx: Arc<Context>, ws: WebSocketStream<Upgraded>, frames: Option<Duration>) {
+    let (mut tx, mut rx) = ws.split();
+    let mut status = ctx.status.clone();
+    let mut frame_timer = frames.map(tokio::time::interval);
+    let mut last_frame: Vec<u8> = vec![];
+
+    let current = status.borrow_and_update().clone();
+    if let Some(s) = current {
+        if tx.send(WsEvent::Status(&s).to_message()).await.is_err() {
+            return;
+        }
+    }
+
+    loop {
+        let next_frame = async {
+            match frame_timer.as_mut() {
+                Some(timer) => timer.tick().await,
+                None => std::future::pending().await,
+            }
+        };
+        let ret = tokio::select! {
+            msg = rx.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let (_, response) = ctx.dispatcher.dispatch(&Source::Http, text.as_bytes()).await;
+                    tx.send(WsEvent::Ack(&response).to_message()).await
+                }
+                Some(Ok(Message::Binary(data))) => {
+                    let (_, response) = ctx.dispatcher.dispatch(&Source::Http, &data).await;
+                    tx.send(WsEvent::Ack(&response).to_message()).await
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                // Pings are answered by tungstenite
+                Some(Ok(_)) => Ok(()),
+                Some(Err(e)) => {
+                    warn!("WebSocket error, '{}'.", e);
+                    break;
+                }
+            },
+            changed = status.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let current = status.borrow_and_update().clone();
+                match current {
+                    Some(s) => tx.send(WsEvent::Status(&s).to_message()).await,
+                    None => Ok(()),
+                }
+            }
+            _ = next_frame => match ctx.monitor.frame_png() {
+                // Skip unchanged frames, the display is mostly static
+                Ok(png) if png != last_frame => {
+                    last_frame = png.clone();
+                    tx.send(Message::Binary(png)).await
+                }
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    warn!("Failed to encode frame, error is '{}'.", e);
+                    Ok(())
+                }
+            },
+        };
+        if ret.is_err() {
+            break;
+        }
+    }
+    info!("WebSocket client disconnected.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_interval() {
+        assert_eq!(frame_interval(None), None);
+        assert_eq!(frame_interval(Some("frames=0")), None);
+        assert_eq!(frame_interval(Some("x=1&frames=4")), Some(Duration::from_millis(250)));
+        assert_eq!(frame_interval(Some("frames=1000")), Some(Duration::from_secs(1) / MAX_FRAME_RATE));
+        assert_eq!(frame_interval(Some("frames=abc")), None);
+    }
+}